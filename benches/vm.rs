@@ -0,0 +1,92 @@
+// Measures how many instructions per second the VM gets through.
+// Run with `cargo bench > /dev/null`; the report goes to stderr while the VM
+// prints each result to stdout.
+//
+// Lox only has arithmetic expressions so far, so these stand in for the
+// usual fib, loop and string-building benchmarks until the compiler grows
+// functions, control flow and strings. The code is straight-line, so every
+// instruction in the chunk runs exactly once per iteration. Constant folding
+// is off, or each benchmark would compile down to a single constant.
+//
+// Each workload runs twice: as compiled, and after fuse_superinstructions,
+// to show what the superinstructions save. Each chunk is verified and loaded
+// once, then timed with `rerun`, so only dispatch is measured, plus printing
+// one number per iteration. The two variants take turns for several rounds
+// after a warm-up, and each reports its fastest round, so neither gains from
+// running second.
+
+use lox_rs::prelude::*;
+use std::time::Instant;
+
+const ITERATIONS: usize = 1_000;
+const ROUNDS: usize = 5;
+
+fn load(chunk: &Chunk) -> VM {
+    let mut vm = init_vm();
+    // Warms up the caches as well as loading the chunk.
+    interpret_chunk(&mut vm, chunk.clone());
+    vm
+}
+
+fn time(vm: &mut VM) -> f64 {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        rerun(vm);
+    }
+    start.elapsed().as_secs_f64()
+}
+
+fn report(name: &str, chunk: &Chunk, seconds: f64) {
+    let instructions = decode_chunk(chunk).len() * ITERATIONS;
+    eprintln!(
+        "{:<16} {:>10} instructions in {:>8.3}ms, {:>8.1}M instructions/s",
+        name,
+        instructions,
+        seconds * 1000.0,
+        instructions as f64 / seconds / 1_000_000.0
+    );
+}
+
+fn bench(name: &str, source: &str) {
+    let mut plain = init_chunk();
+    if !compile_with_options(source, &mut plain, &CompilerOptions::unfolded()) {
+        panic!("Benchmark '{}' failed to compile.", name);
+    }
+    let mut fused = plain.clone();
+    fuse_superinstructions(&mut fused);
+
+    let mut plain_vm = load(&plain);
+    let mut fused_vm = load(&fused);
+    let mut plain_seconds = f64::INFINITY;
+    let mut fused_seconds = f64::INFINITY;
+    for round in 0..ROUNDS {
+        if round % 2 == 0 {
+            plain_seconds = plain_seconds.min(time(&mut plain_vm));
+            fused_seconds = fused_seconds.min(time(&mut fused_vm));
+        } else {
+            fused_seconds = fused_seconds.min(time(&mut fused_vm));
+            plain_seconds = plain_seconds.min(time(&mut plain_vm));
+        }
+    }
+
+    report(name, &plain, plain_seconds);
+    report(&format!("{} (fused)", name), &fused, fused_seconds);
+    eprintln!("{:<16} {:.2}x speedup", "", plain_seconds / fused_seconds);
+}
+
+fn repeat(term: &str, separator: &str, count: usize) -> String {
+    vec![term; count].join(separator)
+}
+
+fn main() {
+    bench("sum", &repeat("1", " + ", 5_000));
+    bench("mixed", &repeat("2 * 3 - 4 / 5", " + ", 1_000));
+    bench("negate", &format!("{}1", repeat("-", "", 1_000)));
+    bench(
+        "long",
+        &(0..5_000)
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(" + "),
+    );
+}
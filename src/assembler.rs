@@ -0,0 +1,236 @@
+use crate::prelude::*;
+
+// Assembly is the disassembler's text format, one instruction per line:
+//
+//   == name ==
+//   0000    1 OP_CONSTANT         0 1.5
+//   0002    | OP_NEGATE
+//   0003    2 OP_RETURN
+//
+// so `write_disassembly` output assembles back into the same chunk. For
+// writing by hand, everything but the opcode is optional:
+//
+//   - the `== name ==` header, blank lines and `;` comments are skipped;
+//   - offsets are ignored, since they follow from the instructions;
+//   - a line number of `|`, or none at all, means the previous line, which
+//     starts at 1;
+//   - an opcode that takes a constant can be given just the value, as in
+//     `OP_CONSTANT 1.5`, to get the first pool slot holding it;
+//   - `Unknown opcode <byte>` writes a raw byte, for making bad chunks;
+//   - a value can be given as its bits, as in `0x7ff8000000000000`, which
+//     is how NaNs are listed.
+//
+// There are no jumps yet, so there are no labels either.
+
+struct Assembler {
+    chunk: Chunk,
+    // Pool slots, which can be claimed out of order by explicit indices.
+    constants: Vec<Option<Value>>,
+    line: usize,
+}
+
+/// Parses assembly into a chunk. The chunk isn't verified, so it can be
+/// anything from a hand-written test case to garbage for fuzzing the VM;
+/// `interpret_chunk` verifies it before running it.
+pub fn assemble(text: &str) -> Result<Chunk, String> {
+    let mut assembler = Assembler {
+        chunk: init_chunk(),
+        constants: vec![],
+        line: 1,
+    };
+
+    for (index, text_line) in text.lines().enumerate() {
+        assemble_line(&mut assembler, text_line)
+            .map_err(|message| format!("Line {}: {}", index + 1, message))?;
+    }
+
+    let mut chunk = assembler.chunk;
+    for (index, constant) in assembler.constants.into_iter().enumerate() {
+        match constant {
+            Some(value) => {
+                push_constant(&mut chunk, value);
+            }
+            None => return Err(format!("Constant {} is never given a value.", index)),
+        }
+    }
+    Ok(chunk)
+}
+
+fn assemble_line(assembler: &mut Assembler, text_line: &str) -> Result<(), String> {
+    let text_line = match text_line.find(';') {
+        Some(comment) => &text_line[..comment],
+        None => text_line,
+    };
+    let tokens: Vec<&str> = text_line.split_whitespace().collect();
+    if tokens.is_empty() || tokens[0] == "==" {
+        return Ok(());
+    }
+
+    // The opcode is the first word; the offset and line come before it.
+    let name_index = tokens
+        .iter()
+        .position(|token| token.starts_with(|c: char| c.is_ascii_alphabetic()))
+        .ok_or("Expected an opcode.")?;
+    let line = match tokens[..name_index] {
+        [] | ["|"] | [_, "|"] => assembler.line,
+        [line] | [_, line] => parse_number(line, "line number")?,
+        _ => return Err("Expected at most an offset and a line before the opcode.".to_string()),
+    };
+    assembler.line = line;
+
+    let name = tokens[name_index];
+    let operands = &tokens[name_index + 1..];
+    if name == "Unknown" {
+        return match operands {
+            ["opcode", byte] => {
+                let byte = parse_number(byte, "byte")?;
+                if byte > u8::MAX as usize {
+                    return Err(format!("{} doesn't fit in a byte.", byte));
+                }
+                write_chunk(&mut assembler.chunk, byte as u8, line);
+                Ok(())
+            }
+            _ => Err("Expected 'Unknown opcode <byte>'.".to_string()),
+        };
+    }
+
+    let op_code = OpCode::from_name(name).ok_or(format!("Unknown opcode '{}'.", name))?;
+    let width = op_code.operand_width();
+    if width == 0 {
+        if !operands.is_empty() {
+            return Err(format!("{} doesn't take an operand.", name));
+        }
+        write_chunk(&mut assembler.chunk, op_code as u8, line);
+        return Ok(());
+    }
+
+    // Explicit indices are checked before they size the pool, so a huge one
+    // is an error rather than a huge allocation.
+    let fits = |constant: usize| {
+        if constant >> (8 * width) == 0 {
+            Ok(constant)
+        } else {
+            Err(format!(
+                "Constant index {} doesn't fit in {}.",
+                constant, name
+            ))
+        }
+    };
+    let constant = match *operands {
+        [value] => fits(find_constant(assembler, parse_value(value)?))?,
+        [index, value] => {
+            let index = fits(parse_number(index, "constant index")?)?;
+            claim_constant(assembler, index, parse_value(value)?)?;
+            index
+        }
+        _ => return Err(format!("{} takes a constant.", name)),
+    };
+
+    write_chunk(&mut assembler.chunk, op_code as u8, line);
+    for byte in (0..width).rev() {
+        write_chunk(&mut assembler.chunk, (constant >> (8 * byte)) as u8, line);
+    }
+    Ok(())
+}
+
+fn find_constant(assembler: &mut Assembler, value: Value) -> usize {
+    let existing = assembler
+        .constants
+        .iter()
+        .position(|constant| constant.map(f64::to_bits) == Some(value.to_bits()));
+    existing.unwrap_or_else(|| {
+        assembler.constants.push(Some(value));
+        assembler.constants.len() - 1
+    })
+}
+
+fn claim_constant(assembler: &mut Assembler, index: usize, value: Value) -> Result<(), String> {
+    if index >= assembler.constants.len() {
+        assembler.constants.resize(index + 1, None);
+    }
+    match assembler.constants[index] {
+        Some(existing) if existing.to_bits() != value.to_bits() => Err(format!(
+            "Constant {} is already {}, not {}.",
+            index, existing, value
+        )),
+        _ => {
+            assembler.constants[index] = Some(value);
+            Ok(())
+        }
+    }
+}
+
+fn parse_number(token: &str, what: &str) -> Result<usize, String> {
+    token
+        .parse()
+        .map_err(|_| format!("Expected a {}, got '{}'.", what, token))
+}
+
+fn parse_value(token: &str) -> Result<Value, String> {
+    let value = match token.strip_prefix("0x") {
+        Some(bits) => u64::from_str_radix(bits, 16).map(Value::from_bits).ok(),
+        None => token.parse().ok(),
+    };
+    value.ok_or(format!("Expected a number, got '{}'.", token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_disassembly() {
+        let mut chunk = init_chunk();
+        let terms: Vec<String> = (0..300).map(|n| format!("{}.5", n)).collect();
+        assert!(compile_with_options(
+            &terms.join(" +\n"),
+            &mut chunk,
+            &CompilerOptions::unfolded()
+        ));
+        fuse_superinstructions(&mut chunk);
+        // The values that don't print as themselves. Two different NaNs, so
+        // the bits have to survive and not just NaN-ness.
+        for &value in &[f64::NAN, -f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let constant = add_constant(&mut chunk, value);
+            write_chunk(&mut chunk, OpCode::ConstantLong as u8, 301);
+            for byte in (0..3).rev() {
+                write_chunk(&mut chunk, (constant >> (8 * byte)) as u8, 301);
+            }
+        }
+
+        let mut text = vec![];
+        write_disassembly(&mut text, &chunk, "test").unwrap();
+        let assembled = assemble(&String::from_utf8(text).unwrap()).unwrap();
+        assert_eq!(assembled.code, chunk.code);
+        assert_eq!(assembled.lines, chunk.lines);
+        let bits = |chunk: &Chunk| -> Vec<u64> {
+            chunk
+                .constants
+                .iter()
+                .map(|value| value.to_bits())
+                .collect()
+        };
+        assert_eq!(bits(&assembled), bits(&chunk));
+    }
+
+    #[test]
+    fn rejects_indices_too_big_for_the_operand() {
+        for text in &[
+            "OP_CONSTANT 256 1",
+            "OP_CONSTANT 99999999999 1",
+            "OP_CONSTANT 18446744073709551615 1",
+            "OP_CONSTANT_LONG 16777216 1",
+        ] {
+            let message = assemble(text).err().expect("Assembled a bad index.");
+            assert!(message.contains("doesn't fit"), "{}: {}", text, message);
+        }
+    }
+
+    #[test]
+    fn rejects_conflicting_constants() {
+        assert_eq!(
+            assemble("OP_CONSTANT 0 1\nOP_CONSTANT 0 2").err(),
+            Some("Line 2: Constant 0 is already 1, not 2.".to_string())
+        );
+    }
+}
@@ -1,17 +1,104 @@
 use crate::prelude::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
-pub const OP_CONSTANT: u8 = 0;
-pub const OP_ADD: u8 = 1;
-pub const OP_SUBTRACT: u8 = 2;
-pub const OP_MULTIPLY: u8 = 3;
-pub const OP_DIVIDE: u8 = 4;
-pub const OP_NEGATE: u8 = 5;
-pub const OP_RETURN: u8 = 6;
+// Declares `OpCode` and `OP_CODES`, the table `TryFrom<u8>` decodes with,
+// from one list, so a new opcode can't be left out of the table and the
+// table can't fall out of order. Opcodes are numbered from 0 in the order
+// they're listed.
+macro_rules! op_codes {
+    ($($name:ident,)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($name,)*
+        }
 
+        // Indexed by opcode byte.
+        const OP_CODES: &[OpCode] = &[$(OpCode::$name,)*];
+    };
+}
+
+op_codes! {
+    Constant,
+    ConstantLong,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Negate,
+    Return,
+
+    // Superinstructions: an arithmetic operator whose right operand is the
+    // constant named by the operand byte.
+    AddConstant,
+    SubtractConstant,
+    MultiplyConstant,
+    DivideConstant,
+}
+
+impl OpCode {
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::ConstantLong => "OP_CONSTANT_LONG",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Return => "OP_RETURN",
+            OpCode::AddConstant => "OP_ADD_CONSTANT",
+            OpCode::SubtractConstant => "OP_SUBTRACT_CONSTANT",
+            OpCode::MultiplyConstant => "OP_MULTIPLY_CONSTANT",
+            OpCode::DivideConstant => "OP_DIVIDE_CONSTANT",
+        }
+    }
+
+    /// The opcode called `name` in disassembly, such as "OP_ADD".
+    pub fn from_name(name: &str) -> Option<OpCode> {
+        OP_CODES
+            .iter()
+            .copied()
+            .find(|op_code| op_code.name() == name)
+    }
+
+    /// How many operand bytes follow the opcode in the code.
+    pub fn operand_width(self) -> usize {
+        match self {
+            OpCode::Constant
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant
+            | OpCode::MultiplyConstant
+            | OpCode::DivideConstant => 1,
+            OpCode::ConstantLong => 3,
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Negate
+            | OpCode::Return => 0,
+        }
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    /// Fails with the byte itself when it isn't a known opcode.
+    fn try_from(byte: u8) -> Result<OpCode, u8> {
+        OP_CODES.get(byte as usize).copied().ok_or(byte)
+    }
+}
+
+#[derive(Clone)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub lines: Vec<usize>,
     pub constants: Vec<Value>,
+    // Keyed on the value's bits, so NaN finds itself and -0.0 and 0.0 stay
+    // separate constants.
+    constant_indices: HashMap<u64, usize>,
 }
 
 pub fn init_chunk() -> Chunk {
@@ -19,6 +106,7 @@ pub fn init_chunk() -> Chunk {
         code: vec![],
         lines: vec![],
         constants: vec![],
+        constant_indices: HashMap::new(),
     }
 }
 
@@ -31,7 +119,97 @@ pub fn write_chunk(chunk: &mut Chunk, byte: u8, line: usize) {
     chunk.lines.push(line);
 }
 
+/// Returns the index of `value` in the constant pool, only adding it if an
+/// identical constant isn't already there.
 pub fn add_constant(chunk: &mut Chunk, value: Value) -> usize {
+    if let Some(&index) = chunk.constant_indices.get(&value.to_bits()) {
+        return index;
+    }
+
     chunk.constants.push(value);
-    chunk.constants.len() - 1
+    let index = chunk.constants.len() - 1;
+    chunk.constant_indices.insert(value.to_bits(), index);
+    index
+}
+
+/// Appends `value` to the constant pool even if it's already there, for
+/// loading a pool whose indices the code already refers to. Later calls to
+/// `add_constant` reuse the first copy.
+pub fn push_constant(chunk: &mut Chunk, value: Value) -> usize {
+    chunk.constants.push(value);
+    let index = chunk.constants.len() - 1;
+    chunk
+        .constant_indices
+        .entry(value.to_bits())
+        .or_insert(index);
+    index
+}
+
+/// Drops every constant from `length` on, for when the code that used them
+/// has been thrown away.
+pub fn truncate_constants(chunk: &mut Chunk, length: usize) {
+    for value in chunk.constants.drain(length..) {
+        // A duplicate from push_constant may still be in use below `length`.
+        if chunk.constant_indices.get(&value.to_bits()) >= Some(&length) {
+            chunk.constant_indices.remove(&value.to_bits());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_decode_to_themselves() {
+        for &op_code in OP_CODES {
+            assert_eq!(OpCode::try_from(op_code as u8), Ok(op_code));
+        }
+        assert_eq!(
+            OpCode::try_from(OP_CODES.len() as u8),
+            Err(OP_CODES.len() as u8)
+        );
+    }
+
+    #[test]
+    fn repeated_literals_share_a_constant() {
+        let mut chunk = init_chunk();
+        assert!(compile_with_options(
+            "1 + 1 + 1",
+            &mut chunk,
+            &CompilerOptions::unfolded()
+        ));
+        assert_eq!(chunk.constants.len(), 1);
+    }
+
+    #[test]
+    fn nan_is_reused() {
+        let mut chunk = init_chunk();
+        let first = add_constant(&mut chunk, f64::NAN);
+        let second = add_constant(&mut chunk, f64::NAN);
+        assert_eq!(first, second);
+        assert_eq!(chunk.constants.len(), 1);
+    }
+
+    #[test]
+    fn pushed_constants_are_reused() {
+        let mut chunk = init_chunk();
+        push_constant(&mut chunk, 0.0);
+        push_constant(&mut chunk, 0.0);
+        assert_eq!(add_constant(&mut chunk, 0.0), 0);
+        assert_eq!(chunk.constants.len(), 2);
+
+        truncate_constants(&mut chunk, 1);
+        assert_eq!(add_constant(&mut chunk, 0.0), 0);
+        assert_eq!(chunk.constants.len(), 1);
+    }
+
+    #[test]
+    fn signed_zeros_stay_separate() {
+        let mut chunk = init_chunk();
+        let positive = add_constant(&mut chunk, 0.0);
+        let negative = add_constant(&mut chunk, -0.0);
+        assert_ne!(positive, negative);
+        assert_eq!(chunk.constants[negative].to_bits(), (-0.0f64).to_bits());
+    }
 }
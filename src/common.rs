@@ -1,2 +0,0 @@
-pub const DEBUG_PRINT_CODE: bool = true;
-pub const DEBUG_TRACE_EXECUTION: bool = true;
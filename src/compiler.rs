@@ -5,11 +5,52 @@ struct Parser<'source> {
     previous: Token<'source>,
     had_error: bool,
     panic_mode: bool,
+    fold_constants: bool,
+    print_code: bool,
+    // Set while the code emitted so far ends with a lone constant.
+    last_constant: Option<ConstantOperand>,
 
     // temp
     compiling_chunk: &'source mut Chunk,
 }
 
+#[derive(Clone, Copy)]
+struct ConstantOperand {
+    value: Value,
+    // Where its instruction starts, and how big the constant pool was before
+    // it, so folding can throw both away again.
+    start: usize,
+    constant_count: usize,
+}
+
+pub struct CompilerOptions {
+    /// Evaluate arithmetic on literals at compile time, so `-(1 + 2) * 3`
+    /// becomes a single constant.
+    pub fold_constants: bool,
+    /// Disassemble the chunk once it compiles.
+    pub print_code: bool,
+}
+
+impl CompilerOptions {
+    /// The defaults without constant folding, so every operator in the
+    /// source keeps its own instruction and line.
+    pub fn unfolded() -> CompilerOptions {
+        CompilerOptions {
+            fold_constants: false,
+            ..CompilerOptions::default()
+        }
+    }
+}
+
+impl Default for CompilerOptions {
+    fn default() -> CompilerOptions {
+        CompilerOptions {
+            fold_constants: true,
+            print_code: false,
+        }
+    }
+}
+
 #[derive(PartialEq, PartialOrd)]
 enum Precedence {
     None = 0,
@@ -110,6 +151,7 @@ fn consume<'source>(
 fn emit_byte(parser: &mut Parser, byte: u8) {
     let previous_token = parser.previous.clone();
     write_chunk(current_chunk(parser), byte, previous_token.line);
+    parser.last_constant = None;
 }
 
 fn emit_bytes(parser: &mut Parser, byte_1: u8, byte_2: u8) {
@@ -118,42 +160,90 @@ fn emit_bytes(parser: &mut Parser, byte_1: u8, byte_2: u8) {
 }
 
 fn emit_return(parser: &mut Parser) {
-    emit_byte(parser, OP_RETURN);
+    emit_byte(parser, OpCode::Return as u8);
 }
 
-fn make_constant(parser: &mut Parser, value: Value) -> u8 {
+// OP_CONSTANT_LONG has a 24-bit operand.
+const MAX_LONG_CONSTANT: usize = 0xff_ffff;
+
+fn make_constant(parser: &mut Parser, value: Value) -> usize {
     let constant = add_constant(parser.compiling_chunk, value);
-    if constant > u8::MAX as usize {
+    if constant > MAX_LONG_CONSTANT {
         error(parser, "Too many constants in one chunk.");
         0
     } else {
-        constant as u8
+        constant
     }
 }
 
 fn emit_constant(parser: &mut Parser, value: Value) {
+    let operand = ConstantOperand {
+        value,
+        start: parser.compiling_chunk.code.len(),
+        constant_count: parser.compiling_chunk.constants.len(),
+    };
+
     let constant = make_constant(parser, value);
-    emit_bytes(parser, OP_CONSTANT, constant);
+    if constant <= u8::MAX as usize {
+        emit_bytes(parser, OpCode::Constant as u8, constant as u8);
+    } else {
+        emit_byte(parser, OpCode::ConstantLong as u8);
+        emit_bytes(parser, (constant >> 16) as u8, (constant >> 8) as u8);
+        emit_byte(parser, constant as u8);
+    }
+
+    parser.last_constant = Some(operand);
+}
+
+// Returns the constant that the code emitted since `start` consists of, if
+// it's exactly one.
+fn constant_since(parser: &Parser, start: usize) -> Option<ConstantOperand> {
+    match parser.last_constant {
+        Some(operand) if parser.fold_constants && operand.start == start => Some(operand),
+        _ => None,
+    }
+}
+
+// Throws away the code from `operand` on, along with any constants only it
+// and the code after it added, and emits `value` in its place.
+fn replace_with_constant(parser: &mut Parser, operand: ConstantOperand, value: Value) {
+    let chunk = current_chunk(parser);
+    chunk.code.truncate(operand.start);
+    chunk.lines.truncate(operand.start);
+    truncate_constants(chunk, operand.constant_count);
+
+    emit_constant(parser, value);
 }
 
 fn end_compiler(parser: &mut Parser) {
     emit_return(parser);
-    if DEBUG_PRINT_CODE && !parser.had_error {
+    if parser.print_code && !parser.had_error {
         disassemble_chunk(parser.compiling_chunk, "code");
     }
 }
 
 fn binary<'source>(parser: &mut Parser<'source>, scanner: &mut Scanner<'source>) {
     let operator_type = parser.previous.token_type.clone();
+    let left = parser.last_constant;
+    let right_start = parser.compiling_chunk.code.len();
+
     let rule = get_rule(operator_type.clone());
     parse_precedence(parser, scanner, increment_precedence(&rule.precedence));
 
-    match operator_type {
-        TokenType::Plus => emit_byte(parser, OP_ADD),
-        TokenType::Minus => emit_byte(parser, OP_SUBTRACT),
-        TokenType::Star => emit_byte(parser, OP_MULTIPLY),
-        TokenType::Slash => emit_byte(parser, OP_DIVIDE),
-        _ => ()
+    let op_code = match operator_type {
+        TokenType::Plus => OpCode::Add,
+        TokenType::Minus => OpCode::Subtract,
+        TokenType::Star => OpCode::Multiply,
+        TokenType::Slash => OpCode::Divide,
+        _ => return,
+    };
+
+    match (left, constant_since(parser, right_start)) {
+        (Some(left), Some(right)) => {
+            let value = evaluate_binary(op_code, left.value, right.value);
+            replace_with_constant(parser, left, value);
+        }
+        _ => emit_byte(parser, op_code as u8),
     }
 }
 
@@ -169,16 +259,19 @@ fn number(parser: &mut Parser, _scanner: &mut Scanner) {
 
 fn unary<'source>(parser: &mut Parser<'source>, scanner: &mut Scanner<'source>) {
     let operator_type = parser.previous.token_type.clone();
+    let operand_start = parser.compiling_chunk.code.len();
 
     parse_precedence(parser, scanner, Precedence::Unary);
 
-    match operator_type {
-        TokenType::Minus => emit_byte(parser, OP_NEGATE),
-        _ => ()
+    if operator_type == TokenType::Minus {
+        match constant_since(parser, operand_start) {
+            Some(operand) => replace_with_constant(parser, operand, -operand.value),
+            None => emit_byte(parser, OpCode::Negate as u8),
+        }
     }
 }
 
-const RULES: [ParseRule; 40] = [
+const RULES: [ParseRule; 42] = [
     ParseRule { prefix: Some(grouping), infix: None, precedence: Precedence::None },
     ParseRule { prefix: None, infix: None, precedence: Precedence::None },
     ParseRule { prefix: None, infix: None, precedence: Precedence::None },
@@ -223,6 +316,9 @@ const RULES: [ParseRule; 40] = [
 
     ParseRule { prefix: None, infix: None, precedence: Precedence::None },
     ParseRule { prefix: None, infix: None, precedence: Precedence::None },
+
+    ParseRule { prefix: None, infix: None, precedence: Precedence::None },
+    ParseRule { prefix: None, infix: None, precedence: Precedence::None },
 ];
 
 fn parse_precedence<'source>(parser: &mut Parser<'source>, scanner: &mut Scanner<'source>, precedence: Precedence) {
@@ -255,6 +351,10 @@ fn expression<'source>(parser: &mut Parser<'source>, scanner: &mut Scanner<'sour
 }
 
 pub fn compile(source: &str, chunk: &mut Chunk) -> bool {
+    compile_with_options(source, chunk, &CompilerOptions::default())
+}
+
+pub fn compile_with_options(source: &str, chunk: &mut Chunk, options: &CompilerOptions) -> bool {
     let mut scanner = init_scanner(source);
     let mut parser = Parser {
         current: Token {
@@ -269,6 +369,9 @@ pub fn compile(source: &str, chunk: &mut Chunk) -> bool {
         },
         had_error: false,
         panic_mode: false,
+        fold_constants: options.fold_constants,
+        print_code: options.print_code,
+        last_constant: None,
 
         compiling_chunk: chunk,
     };
@@ -283,3 +386,57 @@ pub fn compile(source: &str, chunk: &mut Chunk) -> bool {
     end_compiler(&mut parser);
     !parser.had_error
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Compiles `expression` and runs it as far as its OP_RETURN, which is on
+    // the line after it, to get the value the script would print.
+    fn evaluate(expression: &str, fold_constants: bool) -> (Chunk, Value) {
+        let mut chunk = init_chunk();
+        let options = CompilerOptions {
+            fold_constants,
+            ..CompilerOptions::default()
+        };
+        assert!(compile_with_options(
+            &format!("{}\n", expression),
+            &mut chunk,
+            &options
+        ));
+
+        let mut vm = init_vm();
+        set_breakpoint(&mut vm, 2);
+        match interpret_chunk(&mut vm, chunk.clone()) {
+            InterpretResult::Paused(2) => (),
+            _ => panic!("'{}' didn't reach its OP_RETURN.", expression),
+        }
+        let value = *stack(&vm).last().expect("The result is on the stack.");
+        (chunk, value)
+    }
+
+    #[test]
+    fn folding_matches_running_the_code() {
+        for expression in &["-(1 + 2) * 3", "1 / 0", "0 / 0", "-0 * 1", "0.1 + 0.2"] {
+            let (folded, folded_value) = evaluate(expression, true);
+            let (unfolded, unfolded_value) = evaluate(expression, false);
+
+            assert_eq!(
+                folded_value.to_bits(),
+                unfolded_value.to_bits(),
+                "'{}' folds to {} but runs to {}.",
+                expression,
+                folded_value,
+                unfolded_value
+            );
+            assert_eq!(folded.constants.len(), 1, "'{}'", expression);
+            assert_eq!(
+                folded.code,
+                vec![OpCode::Constant as u8, 0, OpCode::Return as u8],
+                "'{}'",
+                expression
+            );
+            assert!(unfolded.code.len() > folded.code.len(), "'{}'", expression);
+        }
+    }
+}
@@ -1,50 +1,314 @@
 use crate::prelude::*;
+use std::convert::TryFrom;
+use std::io::{self, Write};
 
-pub fn disassemble_chunk(chunk: &mut Chunk, name: &str) {
-    println!("== {} ==", name);
+/// One decoded instruction, for tools that want to look at bytecode without
+/// parsing the text listing.
+pub struct Instruction {
+    pub offset: usize,
+    pub line: usize,
+    /// `Err` holds the byte when it isn't a known opcode.
+    pub op_code: Result<OpCode, u8>,
+    /// The operand, decoded from however many bytes the opcode takes. `None`
+    /// if the opcode has no operand or the code ends before it does.
+    pub operand: Option<usize>,
+    /// The constant the operand refers to, for opcodes that take one.
+    pub constant: Option<Value>,
+    /// The size of the instruction in bytes, operand included.
+    pub length: usize,
+}
+
+pub fn decode_instruction(chunk: &Chunk, offset: usize) -> Instruction {
+    let line = chunk.lines[offset];
+    let op_code = match OpCode::try_from(chunk.code[offset]) {
+        Ok(op_code) => op_code,
+        Err(byte) => {
+            return Instruction {
+                offset,
+                line,
+                op_code: Err(byte),
+                operand: None,
+                constant: None,
+                length: 1,
+            }
+        }
+    };
+
+    let width = op_code.operand_width();
+    let operand = match width {
+        0 => None,
+        _ => chunk.code.get(offset + 1..offset + 1 + width).map(|bytes| {
+            bytes
+                .iter()
+                .fold(0, |operand, &byte| operand << 8 | byte as usize)
+        }),
+    };
+    let constant = match op_code {
+        OpCode::Constant
+        | OpCode::ConstantLong
+        | OpCode::AddConstant
+        | OpCode::SubtractConstant
+        | OpCode::MultiplyConstant
+        | OpCode::DivideConstant => operand.and_then(|index| chunk.constants.get(index).copied()),
+        OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Negate
+        | OpCode::Return => None,
+    };
+
+    Instruction {
+        offset,
+        line,
+        op_code: Ok(op_code),
+        operand,
+        constant,
+        length: 1 + width,
+    }
+}
 
+pub fn decode_chunk(chunk: &Chunk) -> Vec<Instruction> {
+    let mut instructions = vec![];
     let mut offset = 0;
     while offset < chunk.code.len() {
-        offset = disassemble_instruction(chunk, offset);
+        let instruction = decode_instruction(chunk, offset);
+        offset += instruction.length;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
+    write_disassembly(&mut io::stdout(), chunk, name).expect("Couldn't write to stdout.");
+}
+
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
+    let instruction = decode_instruction(chunk, offset);
+    let previous_line = if offset > 0 {
+        Some(chunk.lines[offset - 1])
+    } else {
+        None
+    };
+    write_instruction(&mut io::stdout(), &instruction, previous_line)
+        .expect("Couldn't write to stdout.");
+
+    offset + instruction.length
+}
+
+pub fn write_disassembly(out: &mut dyn Write, chunk: &Chunk, name: &str) -> io::Result<()> {
+    writeln!(out, "== {} ==", name)?;
+
+    let mut previous_line = None;
+    for instruction in decode_chunk(chunk) {
+        write_instruction(out, &instruction, previous_line)?;
+        previous_line = Some(instruction.line);
     }
+    Ok(())
 }
 
-pub fn disassemble_instruction(chunk: &mut Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
+/// Writes one line of the text listing. The line number is replaced with a
+/// `|` when it's the same as `previous_line`.
+pub fn write_instruction(
+    out: &mut dyn Write,
+    instruction: &Instruction,
+    previous_line: Option<usize>,
+) -> io::Result<()> {
+    write!(out, "{:04} ", instruction.offset)?;
+
+    if previous_line == Some(instruction.line) {
+        write!(out, "   | ")?;
+    } else {
+        write!(out, "{:4} ", instruction.line)?;
+    }
+
+    let op_code = match instruction.op_code {
+        Ok(op_code) => op_code,
+        Err(byte) => return writeln!(out, "Unknown opcode {}", byte),
+    };
+
+    match (instruction.operand, instruction.constant) {
+        (Some(operand), Some(constant)) => {
+            writeln!(
+                out,
+                "{:<16} {:4} {}",
+                op_code.name(),
+                operand,
+                constant_text(constant)
+            )
+        }
+        (Some(operand), None) => writeln!(out, "{:<16} {:4}", op_code.name(), operand),
+        (None, _) => writeln!(out, "{}", op_code.name()),
+    }
+}
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
+// How constants appear in listings. NaN is written as its bits, like
+// `0x7ff8000000000000`, since there are many NaNs and "NaN" doesn't say
+// which one; the assembler reads the bits back.
+fn constant_text(value: Value) -> String {
+    if value.is_nan() {
+        format!("0x{:016x}", value.to_bits())
     } else {
-        print!("{:4} ", chunk.lines[offset]);
+        format!("{}", value)
     }
+}
 
-    let instruction = chunk.code[offset];
+/// Writes the listing as JSON, one instruction per line so listings diff
+/// cleanly. Constants that JSON can't represent, like NaN, are strings.
+pub fn write_disassembly_json(out: &mut dyn Write, chunk: &Chunk, name: &str) -> io::Result<()> {
+    writeln!(
+        out,
+        "{{\"name\": {}, \"instructions\": [",
+        json_string(name)
+    )?;
 
-    match instruction {
-        OP_CONSTANT => constant_instruction("OP_CONSTANT", &chunk, offset),
-        OP_ADD => simple_instruction("OP_ADD", offset),
-        OP_SUBTRACT => simple_instruction("OP_SUBTRACT", offset),
-        OP_MULTIPLY => simple_instruction("OP_MULTIPLY", offset),
-        OP_DIVIDE => simple_instruction("OP_DIVIDE", offset),
-        OP_NEGATE => simple_instruction("OP_NEGATE", offset),
-        OP_RETURN => simple_instruction("OP_RETURN", offset),
-        _ => {
-            println!("Unknown opcode {}", instruction);
-            offset + 1
+    let instructions = decode_chunk(chunk);
+    for (index, instruction) in instructions.iter().enumerate() {
+        write!(
+            out,
+            "  {{\"offset\": {}, \"line\": {}, ",
+            instruction.offset, instruction.line
+        )?;
+        match instruction.op_code {
+            Ok(op_code) => write!(out, "\"opcode\": {}", json_string(op_code.name()))?,
+            Err(byte) => write!(out, "\"opcode\": null, \"byte\": {}", byte)?,
+        }
+        if let Some(operand) = instruction.operand {
+            write!(out, ", \"operands\": [{}]", operand)?;
         }
+        if let Some(constant) = instruction.constant {
+            write!(out, ", \"constant\": {}", json_number(constant))?;
+        }
+        let separator = if index + 1 < instructions.len() {
+            ","
+        } else {
+            ""
+        };
+        writeln!(out, "}}{}", separator)?;
     }
+
+    writeln!(out, "]}}")
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = chunk.code[offset];
-    print!("{:<16} {:4} ", name, constant);
-    print_value(chunk.constants[constant as usize]);
-    println!("");
+fn json_string(string: &str) -> String {
+    let mut json = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
 
-    offset + 2
+fn json_number(value: Value) -> String {
+    if value.is_finite() {
+        format!("{}", value)
+    } else {
+        json_string(&constant_text(value))
+    }
 }
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
-    offset + 1
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_of(code: &[u8], constants: &[Value]) -> Chunk {
+        let mut chunk = init_chunk();
+        for &byte in code {
+            write_chunk(&mut chunk, byte, 1);
+        }
+        for &value in constants {
+            push_constant(&mut chunk, value);
+        }
+        chunk
+    }
+
+    #[test]
+    fn decodes_unknown_opcodes_as_single_bytes() {
+        let chunk = chunk_of(&[255, OpCode::Return as u8], &[]);
+        let instructions = decode_chunk(&chunk);
+        assert_eq!(instructions.len(), 2);
+        assert_eq!(instructions[0].op_code, Err(255));
+        assert_eq!(instructions[0].operand, None);
+        assert_eq!(instructions[0].length, 1);
+        assert_eq!(instructions[1].op_code, Ok(OpCode::Return));
+    }
+
+    #[test]
+    fn decodes_truncated_operands_as_none() {
+        let chunk = chunk_of(&[OpCode::ConstantLong as u8, 0, 0], &[1.0]);
+        let instruction = decode_instruction(&chunk, 0);
+        assert_eq!(instruction.op_code, Ok(OpCode::ConstantLong));
+        assert_eq!(instruction.operand, None);
+        assert_eq!(instruction.constant, None);
+        assert_eq!(instruction.length, 4);
+        assert_eq!(decode_chunk(&chunk).len(), 1);
+    }
+
+    #[test]
+    fn lists_special_constants() {
+        let chunk = chunk_of(
+            &[
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Constant as u8,
+                2,
+            ],
+            &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY],
+        );
+        let mut text = vec![];
+        write_disassembly(&mut text, &chunk, "special").unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "== special ==\n\
+             0000    1 OP_CONSTANT         0 0x7ff8000000000000\n\
+             0002    | OP_CONSTANT         1 inf\n\
+             0004    | OP_CONSTANT         2 -inf\n"
+        );
+    }
+
+    #[test]
+    fn writes_json() {
+        let chunk = chunk_of(
+            &[
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Add as u8,
+                255,
+                OpCode::Return as u8,
+            ],
+            &[1.5, f64::NAN],
+        );
+        let mut json = vec![];
+        write_disassembly_json(&mut json, &chunk, "a \"chunk\"").unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"name\": \"a \\\"chunk\\\"\", \"instructions\": [\n  \
+             {\"offset\": 0, \"line\": 1, \"opcode\": \"OP_CONSTANT\", \"operands\": [0], \"constant\": 1.5},\n  \
+             {\"offset\": 2, \"line\": 1, \"opcode\": \"OP_CONSTANT\", \"operands\": [1], \"constant\": \"0x7ff8000000000000\"},\n  \
+             {\"offset\": 4, \"line\": 1, \"opcode\": \"OP_ADD\"},\n  \
+             {\"offset\": 5, \"line\": 1, \"opcode\": null, \"byte\": 255},\n  \
+             {\"offset\": 6, \"line\": 1, \"opcode\": \"OP_RETURN\"}\n\
+             ]}\n"
+        );
+    }
+
+    #[test]
+    fn writes_json_for_empty_chunks() {
+        let mut json = vec![];
+        write_disassembly_json(&mut json, &init_chunk(), "empty").unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"name\": \"empty\", \"instructions\": [\n]}\n"
+        );
+    }
 }
@@ -0,0 +1,27 @@
+pub mod assembler;
+pub mod chunk;
+pub mod compiler;
+pub mod debug;
+pub mod optimizer;
+pub mod profiler;
+pub mod scanner;
+pub mod serialize;
+#[cfg(test)]
+mod testing;
+pub mod value;
+pub mod verifier;
+pub mod vm;
+
+pub mod prelude {
+    pub use crate::assembler::*;
+    pub use crate::chunk::*;
+    pub use crate::compiler::*;
+    pub use crate::debug::*;
+    pub use crate::optimizer::*;
+    pub use crate::profiler::*;
+    pub use crate::scanner::*;
+    pub use crate::serialize::*;
+    pub use crate::value::*;
+    pub use crate::verifier::*;
+    pub use crate::vm::*;
+}
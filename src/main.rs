@@ -1,64 +1,635 @@
-extern crate text_io;
-
-mod chunk;
-mod common;
-mod compiler;
-mod debug;
-mod scanner;
-mod value;
-mod vm;
-
-mod prelude {
-    pub use crate::chunk::*;
-    pub use crate::common::*;
-    pub use crate::compiler::*;
-    pub use crate::debug::*;
-    pub use crate::scanner::*;
-    pub use crate::value::*;
-    pub use crate::vm::*;
-}
-
-use prelude::*;
+use lox_rs::prelude::*;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 use std::env;
 use std::fs;
-use std::io::{stdout, Write};
+use std::io::{self, Read};
+use std::path::PathBuf;
 use std::process;
-use text_io::read;
+use std::time::Instant;
 
-fn repl() {
-    let mut vm = init_vm();
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".lox_history"))
+}
+
+// Whether the input so far leaves a bracket or string open, so the REPL
+// should keep reading lines before running it.
+fn is_incomplete(source: &str) -> bool {
+    let mut depth = 0;
+    for token in tokenize(source) {
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+            TokenType::Error if token.token == "Unterminated string." => return true,
+            _ => (),
+        }
+    }
+    depth > 0
+}
+
+const REPL_HELP: &str = "\
+:dis <expr>       show the bytecode for an expression
+:trace on|off     print each instruction as it runs
+:globals          show global variables
+:load <path>      run a file in this session
+:reset            start over with a fresh VM
+:time <expr>      run an expression and report how long it took
+:quit             leave the REPL";
+
+// Splits a line such as `:dis 1 + 2` into the command and its argument.
+fn split_command(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    }
+}
+
+fn print_stack(vm: &VM) {
+    for slot in stack(vm) {
+        print!("[ ");
+        print_value(*slot);
+        print!(" ]");
+    }
+    println!();
+}
+
+// Runs a REPL command such as `:dis 1 + 2`.
+fn run_command(vm: &mut VM, options: &VmOptions, line: &str) {
+    let (command, argument) = split_command(line);
+
+    match command {
+        ":dis" => {
+            let mut chunk = init_chunk();
+            if compile(argument, &mut chunk) {
+                disassemble_chunk(&chunk, argument);
+            }
+        }
+        ":trace" => match argument {
+            "on" => set_trace_execution(vm, true),
+            "off" => set_trace_execution(vm, false),
+            _ => eprintln!("Usage: :trace on|off"),
+        },
+        ":globals" => println!("Lox doesn't have global variables yet."),
+        ":load" => match fs::read_to_string(argument) {
+            Ok(source) => {
+                interpret(vm, &source);
+            }
+            Err(error) => eprintln!("Couldn't read '{}': {}", argument, error),
+        },
+        ":reset" => *vm = init_vm_with_options(options.clone()),
+        ":time" => {
+            let start = Instant::now();
+            interpret(vm, argument);
+            println!("{:.3}ms", start.elapsed().as_secs_f64() * 1000.0);
+        }
+        ":help" => println!("{}", REPL_HELP),
+        _ => eprintln!("Unknown command '{}'. Try :help.", command),
+    }
+}
+
+// The profile is reported when a script finishes, which a REPL session or a
+// debugger run doesn't really do, so they turn the flag down rather than
+// ignore it.
+fn reject_profile(options: &VmOptions, command: &str) {
+    if options.profile {
+        eprintln!("--profile isn't supported by {}.", command);
+        process::exit(64);
+    }
+}
+
+fn repl(options: VmOptions) {
+    reject_profile(&options, "repl");
+    let mut editor = DefaultEditor::new().expect("Couldn't set up line editing.");
+    let history = history_path();
+    if let Some(ref path) = history {
+        // There's no history the first time around.
+        let _ = editor.load_history(path);
+    }
+
+    let mut vm = init_vm_with_options(options.clone());
+    let mut source = String::new();
     loop {
-        print!("> ");
-        stdout().flush().expect("Failed to flush stdout.");
-        let line: String = read!("{}\n");
+        let prompt = if source.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if source.is_empty() && line.trim_start().starts_with(':') {
+                    let _ = editor.add_history_entry(line.trim());
+                    if line.trim() == ":quit" {
+                        break;
+                    }
+                    run_command(&mut vm, &options, &line);
+                    continue;
+                }
+                source.push_str(&line);
+                source.push('\n');
+                if is_incomplete(&source) {
+                    continue;
+                }
 
-        interpret(&mut vm, &line);
+                let _ = editor.add_history_entry(source.trim_end());
+                interpret(&mut vm, &source);
+                source.clear();
+            }
+            // Ctrl-C throws away whatever has been typed so far.
+            Err(ReadlineError::Interrupted) => source.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("Couldn't read input: {}", error);
+                break;
+            }
+        }
     }
 
-    // free_vm(vm);
+    if let Some(ref path) = history {
+        if let Err(error) = editor.save_history(path) {
+            eprintln!("Couldn't save history to '{}': {}", path.display(), error);
+        }
+    }
+    free_vm(vm);
 }
 
-fn run_file(path: &str) {
-    let mut vm = init_vm();
-    let source = fs::read_to_string(path).expect("Couldn't read file.");
-    let result = interpret(&mut vm, &source);
+const DEBUGGER_HELP: &str = "\
+break <line>      pause whenever the script gets to a line
+clear <line>      remove a breakpoint
+breakpoints       list the breakpoints
+run               run the script from the start
+continue          run until the next breakpoint
+step, next        run the current line and pause at the next one
+finish            run until the script returns, or the next breakpoint
+print <expr>      evaluate an expression
+stack             show the VM's stack
+locals, globals   show variables
+list              show the source around the current line
+quit              leave the debugger";
+
+fn print_source_line(source_lines: &[&str], line: usize) {
+    match line
+        .checked_sub(1)
+        .and_then(|index| source_lines.get(index))
+    {
+        Some(text) => println!("{:4} {}", line, text),
+        None => println!("{:4}", line),
+    }
+}
+
+// Prints up to `context` lines either side of `line`, marking `line` itself.
+fn list_source(source_lines: &[&str], line: usize, context: usize) {
+    let first = line.saturating_sub(context).max(1);
+    for number in first..=line + context {
+        if let Some(text) = source_lines.get(number - 1) {
+            let marker = if number == line { "->" } else { "  " };
+            println!("{} {:4} {}", marker, number, text);
+        }
+    }
+}
+
+// Bytecode files don't carry their source, so `source` is None for them and
+// the debugger can only show line numbers.
+fn debug(path: &str, chunk: Chunk, source: Option<String>, options: VmOptions) {
+    let mut editor = DefaultEditor::new().expect("Couldn't set up line editing.");
+    let source_lines: Vec<&str> = match source {
+        Some(ref source) => source.lines().collect(),
+        None => vec![],
+    };
+    let mut vm = init_vm_with_options(options);
+    println!("Debugging '{}'. Type 'help' for commands.", path);
+
+    loop {
+        let line = match editor.readline("(lox) ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("Couldn't read input: {}", error);
+                break;
+            }
+        };
+        let _ = editor.add_history_entry(line.trim());
+
+        let (command, argument) = split_command(&line);
+        let result = match command {
+            "" => None,
+            "break" | "b" | "clear" => {
+                match argument.parse() {
+                    Ok(line) if command == "clear" => clear_breakpoint(&mut vm, line),
+                    Ok(line) => set_breakpoint(&mut vm, line),
+                    Err(_) => eprintln!("Usage: {} <line>", command),
+                }
+                None
+            }
+            "breakpoints" => {
+                for &line in breakpoints(&vm) {
+                    print_source_line(&source_lines, line);
+                }
+                None
+            }
+            "run" | "r" => Some(interpret_chunk(&mut vm, chunk.clone())),
+            // The script is the only function, so finishing it is continuing.
+            "continue" | "c" | "finish" if paused_line(&vm).is_some() => Some(resume(&mut vm)),
+            "step" | "s" | "next" | "n" => match paused_line(&vm) {
+                Some(_) => Some(step(&mut vm)),
+                None => Some(debug_chunk(&mut vm, chunk.clone())),
+            },
+            "continue" | "c" | "finish" | "list" | "l" if paused_line(&vm).is_none() => {
+                println!("The script isn't running.");
+                None
+            }
+            "list" | "l" if source.is_none() => {
+                println!("There's no source for a bytecode file.");
+                None
+            }
+            "list" | "l" => {
+                let line = paused_line(&vm).expect("Checked it's paused.");
+                list_source(&source_lines, line, 5);
+                None
+            }
+            "print" | "p" => {
+                // There are no variables to see, so the current frame makes
+                // no difference and the expression can run on its own.
+                let mut scratch = init_vm();
+                interpret(&mut scratch, argument);
+                None
+            }
+            "stack" => {
+                print_stack(&vm);
+                None
+            }
+            "locals" => {
+                println!("Lox doesn't have local variables yet.");
+                None
+            }
+            "globals" => {
+                println!("Lox doesn't have global variables yet.");
+                None
+            }
+            "help" => {
+                println!("{}", DEBUGGER_HELP);
+                None
+            }
+            "quit" | "q" => break,
+            _ => {
+                eprintln!("Unknown command '{}'. Try help.", command);
+                None
+            }
+        };
+
+        match result {
+            Some(InterpretResult::Paused(line)) => {
+                println!("Paused at line {}.", line);
+                print_source_line(&source_lines, line);
+            }
+            Some(InterpretResult::Ok) => println!("The script finished."),
+            Some(_) => println!("The script stopped."),
+            None => (),
+        }
+    }
+    free_vm(vm);
+}
+
+const USAGE: &str = "\
+Usage: lox-rs [options] [path [args...]]
+       lox-rs [options] run <path> [args...]
+       lox-rs [options] -e <code> [args...]
+       lox-rs [options] repl
+       lox-rs [options] debug <path>
+       lox-rs [options] disasm [--json] <path>
+       lox-rs [options] check <path>
+       lox-rs [options] compile <path> -o <output>
+       lox-rs assemble <path> -o <output>
+       lox-rs --tokens <path>
+
+A path of - reads from stdin. Paths may be Lox source or compiled bytecode.
+assemble turns disassembly back into bytecode.
+
+Options:
+  -O              optimize the bytecode before running or writing it
+  --print-code    disassemble the code before running it
+  --trace         print each instruction as it runs
+  --profile       report the time spent per opcode, line and function
+  --profile-stacks <path>
+                  profile, and write collapsed stacks for flamegraph tools";
+
+// Exits with 74, EX_IOERR from sysexits.h, like clox does when a file can't
+// be read.
+fn read_file(path: &str) -> Vec<u8> {
+    let result = if path == "-" {
+        let mut contents = vec![];
+        io::stdin().read_to_end(&mut contents).map(|_| contents)
+    } else {
+        fs::read(path)
+    };
+
+    match result {
+        Ok(contents) => contents,
+        Err(error) if path == "-" => {
+            eprintln!("Couldn't read stdin: {}", error);
+            process::exit(74);
+        }
+        Err(error) => {
+            eprintln!("Couldn't read '{}': {}", path, error);
+            process::exit(74);
+        }
+    }
+}
+
+// Source that isn't UTF-8 is bad input rather than an I/O failure, so it
+// exits with 65 like other compile errors.
+fn source_from_bytes(path: &str, contents: Vec<u8>) -> String {
+    match String::from_utf8(contents) {
+        Ok(source) => source,
+        Err(error) => {
+            let bytes = error.as_bytes();
+            let valid = &bytes[..error.utf8_error().valid_up_to()];
+            let line = valid.iter().filter(|&&byte| byte == b'\n').count() + 1;
+            let column = match valid.iter().rposition(|&byte| byte == b'\n') {
+                Some(newline) => valid.len() - newline,
+                None => valid.len() + 1,
+            };
+            let name = if path == "-" {
+                "stdin".to_string()
+            } else {
+                format!("'{}'", path)
+            };
+            eprintln!(
+                "{} isn't valid UTF-8: bad byte 0x{:02x} at line {}, column {}.",
+                name,
+                bytes[valid.len()],
+                line,
+                column
+            );
+            process::exit(65);
+        }
+    }
+}
+
+fn read_source(path: &str) -> String {
+    source_from_bytes(path, read_file(path))
+}
+
+fn compile_source(source: &str) -> Chunk {
+    let mut chunk = init_chunk();
+    if !compile(source, &mut chunk) {
+        process::exit(65);
+    }
+    chunk
+}
+
+// Loads a chunk from either a bytecode file or a source file.
+fn load_chunk(path: &str) -> Chunk {
+    let contents = read_file(path);
+    if contents.starts_with(BYTECODE_MAGIC) {
+        load_bytecode(path, &contents)
+    } else {
+        compile_source(&source_from_bytes(path, contents))
+    }
+}
+
+fn load_bytecode(path: &str, contents: &[u8]) -> Chunk {
+    match deserialize_chunk(contents) {
+        Ok(chunk) => chunk,
+        Err(message) => {
+            eprintln!("Invalid bytecode file '{}': {}", path, message);
+            process::exit(65);
+        }
+    }
+}
+
+// Folding and the optimizer move code onto the operator's line, leaving
+// breakpoints on the operands' lines nothing to stop at, so the debugger
+// compiles without folding and ignores -O.
+fn debug_file(path: &str, optimize: bool, options: VmOptions) {
+    reject_profile(&options, "debug");
+    if optimize {
+        eprintln!("-O is ignored when debugging.");
+    }
+
+    let contents = read_file(path);
+    let (chunk, source) = if contents.starts_with(BYTECODE_MAGIC) {
+        (load_bytecode(path, &contents), None)
+    } else {
+        let source = source_from_bytes(path, contents);
+        let mut chunk = init_chunk();
+        if !compile_with_options(&source, &mut chunk, &CompilerOptions::unfolded()) {
+            process::exit(65);
+        }
+        (chunk, Some(source))
+    };
+    debug(path, chunk, source, options);
+}
+
+// `args` are the script's arguments. Lox has no lists or global variables to
+// hand them over in yet, so for now they're accepted and ignored.
+//
+// When profiling, the report goes to stderr so it doesn't mix with the
+// script's output, and the collapsed stacks to `profile_stacks` if it's set.
+fn run_chunk(
+    mut chunk: Chunk,
+    _args: &[String],
+    optimize: bool,
+    options: VmOptions,
+    profile_stacks: Option<&str>,
+) {
+    if optimize {
+        optimize_chunk(&mut chunk);
+    }
+    if options.print_code {
+        disassemble_chunk(&chunk, "code");
+    }
+    let profiling = options.profile;
+    let mut vm = init_vm_with_options(options);
+    let result = interpret_chunk(&mut vm, chunk);
+    if profiling {
+        write_profile(profile(&vm), profile_stacks);
+    }
     free_vm(vm);
 
     match result {
         InterpretResult::Ok => process::exit(0),
         InterpretResult::CompileError => process::exit(65),
-        InterpretResult::RuntimeError => process::exit(70),
+        InterpretResult::RuntimeError(_)
+        | InterpretResult::BudgetExhausted(_)
+        | InterpretResult::Interrupted(_) => process::exit(70),
+        InterpretResult::Paused(_) => unreachable!("No breakpoints were set."),
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        run_file(&args[1]);
+fn write_profile(profile: &Profile, stacks_path: Option<&str>) {
+    eprintln!();
+    if let Err(error) = write_profile_report(&mut io::stderr(), profile) {
+        eprintln!("Couldn't write the profile: {}", error);
+    }
+
+    if let Some(path) = stacks_path {
+        let mut stacks = vec![];
+        write_collapsed_stacks(&mut stacks, profile).expect("Writing to a Vec can't fail.");
+        if let Err(error) = fs::write(path, stacks) {
+            eprintln!("Couldn't write '{}': {}", path, error);
+            process::exit(74);
+        }
+    }
+}
+
+fn disassemble_file(path: &str, optimize: bool, json: bool) {
+    let mut chunk = load_chunk(path);
+    if optimize {
+        optimize_chunk(&mut chunk);
+    }
+
+    let mut out = io::stdout();
+    let result = if json {
+        write_disassembly_json(&mut out, &chunk, path)
     } else {
-        eprintln!("Usage: lox-rs [path]");
-        process::exit(64);
+        write_disassembly(&mut out, &chunk, path)
+    };
+    if let Err(error) = result {
+        eprintln!("Couldn't write disassembly: {}", error);
+        process::exit(74);
+    }
+}
+
+fn compile_file(path: &str, output_path: &str, optimize: bool, print_code: bool) {
+    let mut chunk = compile_source(&read_source(path));
+    if optimize {
+        optimize_chunk(&mut chunk);
+    }
+    if print_code {
+        disassemble_chunk(&chunk, "code");
+    }
+    save_chunk(&chunk, output_path);
+}
+
+// Unlike the library's assemble, this only writes chunks that would load
+// again.
+fn assemble_file(path: &str, output_path: &str) {
+    let chunk = match assemble(&read_source(path)) {
+        Ok(chunk) => chunk,
+        Err(message) => {
+            eprintln!("Couldn't assemble '{}': {}", path, message);
+            process::exit(65);
+        }
+    };
+    if let Err(message) = verify_chunk(&chunk) {
+        eprintln!("Invalid chunk: {}", message);
+        process::exit(65);
+    }
+    save_chunk(&chunk, output_path);
+}
+
+fn save_chunk(chunk: &Chunk, output_path: &str) {
+    if let Err(error) = fs::write(output_path, serialize_chunk(chunk)) {
+        eprintln!("Couldn't write '{}': {}", output_path, error);
+        process::exit(74);
+    }
+}
+
+fn dump_tokens(path: &str) {
+    let source = read_source(path);
+
+    let mut line = 0;
+    for token in tokenize(&source) {
+        if token.line != line {
+            print!("{:4} ", token.line);
+            line = token.line;
+        } else {
+            print!("   | ");
+        }
+        println!("{:?} '{}'", token.token_type, token.token);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(64);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // Options come before the command or path, so anything after the script
+    // belongs to the script.
+    let mut optimize = false;
+    let mut options = VmOptions::default();
+    let mut code = None;
+    let mut profile_stacks = None;
+    let mut index = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "-O" => optimize = true,
+            "--print-code" => options.print_code = true,
+            "--trace" => options.trace_execution = true,
+            "--profile" => options.profile = true,
+            "--profile-stacks" => {
+                index += 1;
+                options.profile = true;
+                profile_stacks = Some(args.get(index).unwrap_or_else(|| usage()).as_str());
+            }
+            "-e" => {
+                index += 1;
+                code = Some(args.get(index).unwrap_or_else(|| usage()));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => break,
+        }
+        index += 1;
+    }
+    let args = &args[index..];
+
+    if let Some(code) = code {
+        run_chunk(
+            compile_source(code),
+            args,
+            optimize,
+            options,
+            profile_stacks,
+        );
+        return;
+    }
+
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => {
+            repl(options);
+            return;
+        }
+    };
+    match (command, rest) {
+        ("repl", []) => repl(options),
+        ("run", [path, script_args @ ..]) => run_chunk(
+            load_chunk(path),
+            script_args,
+            optimize,
+            options,
+            profile_stacks,
+        ),
+        ("debug", [path]) => debug_file(path, optimize, options),
+        ("disasm", [path]) => disassemble_file(path, optimize, false),
+        ("disasm", [flag, path]) if flag == "--json" => disassemble_file(path, optimize, true),
+        ("check", [path]) => {
+            load_chunk(path);
+        }
+        ("compile", [path, flag, output]) if flag == "-o" => {
+            compile_file(path, output, optimize, options.print_code)
+        }
+        ("assemble", [path, flag, output]) if flag == "-o" => assemble_file(path, output),
+        ("--tokens", [path]) => dump_tokens(path),
+        ("repl", _)
+        | ("run", _)
+        | ("debug", _)
+        | ("disasm", _)
+        | ("check", _)
+        | ("compile", _)
+        | ("assemble", _) => usage(),
+        (path, script_args) if path == "-" || !path.starts_with('-') => run_chunk(
+            load_chunk(path),
+            script_args,
+            optimize,
+            options,
+            profile_stacks,
+        ),
+        _ => usage(),
     }
 }
@@ -0,0 +1,325 @@
+use crate::prelude::*;
+use std::convert::TryFrom;
+
+struct Instruction {
+    op_code: OpCode,
+    // Set for OP_CONSTANT and OP_CONSTANT_LONG.
+    constant: Option<Value>,
+    line: usize,
+}
+
+/// Rewrites a chunk in place with some cheap peephole optimizations:
+///
+/// - arithmetic on constants, like `OP_CONSTANT 1; OP_CONSTANT 2; OP_ADD`,
+///   becomes a single constant, repeatedly, so whole constant expressions
+///   collapse;
+/// - code after the first OP_RETURN is unreachable and is dropped;
+/// - what's left goes through `fuse_superinstructions`.
+///
+/// The constant pool is rebuilt so that constants nothing refers to anymore
+/// go away. Folded instructions keep the line of the operator that produced
+/// them. There are no OP_NOT or jump instructions yet, so there's nothing to
+/// do for double negations or jump chains.
+///
+/// The chunk must have passed `verify_chunk`.
+pub fn optimize_chunk(chunk: &mut Chunk) {
+    let mut instructions: Vec<Instruction> = vec![];
+
+    for instruction in decode(chunk) {
+        let folded = match instruction.op_code {
+            OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide => {
+                fold_binary(&mut instructions, instruction.op_code)
+            }
+            OpCode::Negate => match instructions.last().and_then(|last| last.constant) {
+                Some(value) => {
+                    instructions.pop();
+                    Some(-value)
+                }
+                None => None,
+            },
+            _ => None,
+        };
+
+        let is_return = instruction.op_code == OpCode::Return;
+        instructions.push(match folded {
+            Some(value) => Instruction {
+                op_code: OpCode::Constant,
+                constant: Some(value),
+                line: instruction.line,
+            },
+            None => instruction,
+        });
+        if is_return {
+            break;
+        }
+    }
+
+    *chunk = encode(instructions);
+}
+
+/// Replaces an OP_CONSTANT followed by an arithmetic operator with the
+/// matching superinstruction, such as OP_ADD_CONSTANT, saving a dispatch and
+/// a push. Only constants whose index fits in a byte are fused.
+///
+/// The chunk must have passed `verify_chunk`.
+pub fn fuse_superinstructions(chunk: &mut Chunk) {
+    *chunk = encode(decode(chunk));
+}
+
+fn with_constant_operand(op_code: OpCode) -> Option<OpCode> {
+    match op_code {
+        OpCode::Add => Some(OpCode::AddConstant),
+        OpCode::Subtract => Some(OpCode::SubtractConstant),
+        OpCode::Multiply => Some(OpCode::MultiplyConstant),
+        OpCode::Divide => Some(OpCode::DivideConstant),
+        _ => None,
+    }
+}
+
+fn without_constant_operand(op_code: OpCode) -> Option<OpCode> {
+    match op_code {
+        OpCode::AddConstant => Some(OpCode::Add),
+        OpCode::SubtractConstant => Some(OpCode::Subtract),
+        OpCode::MultiplyConstant => Some(OpCode::Multiply),
+        OpCode::DivideConstant => Some(OpCode::Divide),
+        _ => None,
+    }
+}
+
+fn fold_binary(instructions: &mut Vec<Instruction>, op_code: OpCode) -> Option<Value> {
+    if instructions.len() < 2 {
+        return None;
+    }
+    let a = instructions[instructions.len() - 2].constant?;
+    let b = instructions[instructions.len() - 1].constant?;
+    instructions.truncate(instructions.len() - 2);
+
+    Some(evaluate_binary(op_code, a, b))
+}
+
+/// Does what the VM would do for a binary operator, for folding constants
+/// ahead of time.
+pub fn evaluate_binary(op_code: OpCode, a: Value, b: Value) -> Value {
+    match op_code {
+        OpCode::Add => a + b,
+        OpCode::Subtract => a - b,
+        OpCode::Multiply => a * b,
+        OpCode::Divide => a / b,
+        _ => panic!("{} isn't a binary operator.", op_code.name()),
+    }
+}
+
+// Superinstructions come back split into their constant and operator, so the
+// passes only have to deal with one form.
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op_code = OpCode::try_from(chunk.code[offset]).expect("Chunk wasn't verified.");
+        let operands = &chunk.code[offset + 1..offset + 1 + op_code.operand_width()];
+        let line = chunk.lines[offset];
+        let constant = match op_code.operand_width() {
+            0 => None,
+            _ => {
+                let index = operands
+                    .iter()
+                    .fold(0, |index, &byte| index << 8 | byte as usize);
+                Some(chunk.constants[index])
+            }
+        };
+
+        match without_constant_operand(op_code) {
+            Some(operator) => {
+                instructions.push(Instruction {
+                    op_code: OpCode::Constant,
+                    constant,
+                    line,
+                });
+                instructions.push(Instruction {
+                    op_code: operator,
+                    constant: None,
+                    line,
+                });
+            }
+            None => instructions.push(Instruction {
+                op_code,
+                constant,
+                line,
+            }),
+        }
+        offset += 1 + op_code.operand_width();
+    }
+    instructions
+}
+
+fn encode(instructions: Vec<Instruction>) -> Chunk {
+    let mut chunk = init_chunk();
+    let mut instructions = instructions.into_iter().peekable();
+    while let Some(instruction) = instructions.next() {
+        let value = match instruction.constant {
+            Some(value) => value,
+            None => {
+                write_chunk(&mut chunk, instruction.op_code as u8, instruction.line);
+                continue;
+            }
+        };
+
+        let fused = instructions
+            .peek()
+            .and_then(|next| with_constant_operand(next.op_code));
+        let constant = add_constant(&mut chunk, value);
+        match fused {
+            Some(fused) if constant <= u8::MAX as usize => {
+                // Errors in the fused instruction belong to the operator.
+                let line = instructions.next().expect("Peeked it.").line;
+                write_chunk(&mut chunk, fused as u8, line);
+                write_chunk(&mut chunk, constant as u8, line);
+            }
+            _ => write_constant(&mut chunk, constant, instruction.line),
+        }
+    }
+    chunk
+}
+
+fn write_constant(chunk: &mut Chunk, constant: usize, line: usize) {
+    if constant <= u8::MAX as usize {
+        write_chunk(chunk, OpCode::Constant as u8, line);
+        write_chunk(chunk, constant as u8, line);
+    } else {
+        write_chunk(chunk, OpCode::ConstantLong as u8, line);
+        write_chunk(chunk, (constant >> 16) as u8, line);
+        write_chunk(chunk, (constant >> 8) as u8, line);
+        write_chunk(chunk, constant as u8, line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn optimized(chunk: &Chunk) -> Chunk {
+        let mut chunk = chunk.clone();
+        optimize_chunk(&mut chunk);
+        chunk
+    }
+
+    #[test]
+    fn optimizing_keeps_the_result() {
+        for source in &[
+            "-(1 + 2) * 3 - -4 / 8",
+            "1 / 0 - 1 / 0",
+            "0 / 0",
+            "-0 * 1",
+            "0.1 + 0.2 + 0.3",
+        ] {
+            let chunk = compile_unfolded(source);
+            let plain = run_to_return(&chunk);
+            let optimized = run_to_return(&optimized(&chunk));
+            assert_eq!(plain.to_bits(), optimized.to_bits(), "'{}'", source);
+        }
+    }
+
+    #[test]
+    fn folds_whole_chains() {
+        let chunk = optimized(&compile_unfolded("-(1 + 2) * 3 - -4 / 8 + 1"));
+        assert_eq!(
+            chunk.code,
+            vec![OpCode::Constant as u8, 0, OpCode::Return as u8]
+        );
+        assert_eq!(chunk.constants, vec![-7.5]);
+    }
+
+    #[test]
+    fn drops_code_after_return() {
+        let chunk =
+            assemble("OP_CONSTANT 1\nOP_RETURN\nOP_CONSTANT 2\nOP_NEGATE\nOP_RETURN").unwrap();
+        verify_chunk(&chunk).unwrap();
+
+        let chunk = optimized(&chunk);
+        assert_eq!(
+            chunk.code,
+            vec![OpCode::Constant as u8, 0, OpCode::Return as u8]
+        );
+        assert_eq!(chunk.constants, vec![1.0]);
+    }
+
+    #[test]
+    fn folded_constants_keep_the_operator_line() {
+        let chunk =
+            assemble("1 OP_CONSTANT 1\n2 OP_CONSTANT 2\n3 OP_ADD\n4 OP_NEGATE\n5 OP_RETURN")
+                .unwrap();
+        let chunk = optimized(&chunk);
+        assert_eq!(chunk.lines, vec![4, 4, 5]);
+    }
+
+    #[test]
+    fn handles_long_constants() {
+        // 300 different constants, so the unoptimized chunk needs
+        // OP_CONSTANT_LONG for the later ones.
+        let terms: Vec<String> = (0..300).map(|n| format!("{}.5", n)).collect();
+        let chunk = compile_unfolded(&terms.join(" + "));
+        assert!(chunk.code.contains(&(OpCode::ConstantLong as u8)));
+
+        let optimized = optimized(&chunk);
+        verify_chunk(&optimized).unwrap();
+        assert_eq!(optimized.constants.len(), 1);
+        assert_eq!(
+            run_to_return(&chunk).to_bits(),
+            run_to_return(&optimized).to_bits()
+        );
+    }
+
+    #[test]
+    fn fused_chunks_compute_the_same_bits() {
+        // The constant is the right operand of every fused instruction, so
+        // `-` and `/` catch handlers that get the operands backwards.
+        let long_sum: Vec<String> = (0..300).map(|n| format!("{}.5", n)).collect();
+        for source in &[
+            "10 - 3",
+            "1 / 3",
+            "3 / 1 / 4",
+            "2 * 0.1 + 0.2",
+            "0 / 0",
+            "-(0 / 0) * 1",
+            "1 / 0 - 1 / 0",
+            "-0 - 0",
+            "1 / -0 / 2",
+            &long_sum.join(" + "),
+        ] {
+            let plain = compile_unfolded(source);
+            let mut fused = plain.clone();
+            fuse_superinstructions(&mut fused);
+            assert_ne!(fused.code, plain.code, "'{}' wasn't fused.", source);
+            verify_chunk(&fused).unwrap();
+            assert_eq!(
+                run_to_return(&plain).to_bits(),
+                run_to_return(&fused).to_bits(),
+                "'{}'",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn fuses_every_operator() {
+        let mut chunk = compile_unfolded("1 + 2 - 3 * 4 / 5");
+        fuse_superinstructions(&mut chunk);
+        let op_codes: Vec<_> = decode_chunk(&chunk)
+            .into_iter()
+            .map(|instruction| instruction.op_code)
+            .collect();
+        assert_eq!(
+            op_codes,
+            vec![
+                Ok(OpCode::Constant),
+                Ok(OpCode::AddConstant),
+                Ok(OpCode::Constant),
+                Ok(OpCode::MultiplyConstant),
+                Ok(OpCode::DivideConstant),
+                Ok(OpCode::Subtract),
+                Ok(OpCode::Return),
+            ]
+        );
+    }
+}
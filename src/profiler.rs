@@ -0,0 +1,260 @@
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// How often something ran and how long it took.
+#[derive(Clone, Copy, Default)]
+pub struct ProfileEntry {
+    pub count: u64,
+    pub time: Duration,
+}
+
+/// What a VM with `VmOptions::profile` set has run, across every run since it
+/// was created or `reset_profile` was called.
+///
+/// Each instruction's time runs from its dispatch to the next one, so it
+/// includes the profiler's own overhead, which is about the same for every
+/// instruction. Everything runs in the top-level script until Lox has
+/// functions, so that's the only function and the only stack frame.
+#[derive(Clone, Default)]
+pub struct Profile {
+    // Keyed by line and opcode byte, from which the other views are summed.
+    entries: BTreeMap<(usize, u8), ProfileEntry>,
+}
+
+pub fn record_instruction(profile: &mut Profile, line: usize, op_code: OpCode, time: Duration) {
+    let entry = profile.entries.entry((line, op_code as u8)).or_default();
+    entry.count += 1;
+    entry.time += time;
+}
+
+pub fn clear_profile(profile: &mut Profile) {
+    profile.entries.clear();
+}
+
+/// Totals per opcode, slowest first.
+pub fn profile_by_opcode(profile: &Profile) -> Vec<(OpCode, ProfileEntry)> {
+    let mut totals: BTreeMap<u8, ProfileEntry> = BTreeMap::new();
+    for (&(_, byte), entry) in &profile.entries {
+        add_entry(totals.entry(byte).or_default(), entry);
+    }
+    let totals = totals
+        .into_iter()
+        .map(|(byte, entry)| (OpCode::try_from(byte).expect("Recorded opcode."), entry))
+        .collect();
+    sorted(totals)
+}
+
+/// Totals per source line, slowest first.
+pub fn profile_by_line(profile: &Profile) -> Vec<(usize, ProfileEntry)> {
+    let mut totals: BTreeMap<usize, ProfileEntry> = BTreeMap::new();
+    for (&(line, _), entry) in &profile.entries {
+        add_entry(totals.entry(line).or_default(), entry);
+    }
+    sorted(totals.into_iter().collect())
+}
+
+/// Totals per function, slowest first.
+pub fn profile_by_function(profile: &Profile) -> Vec<(String, ProfileEntry)> {
+    let mut total = ProfileEntry::default();
+    for entry in profile.entries.values() {
+        add_entry(&mut total, entry);
+    }
+    if total.count == 0 {
+        vec![]
+    } else {
+        vec![("script".to_string(), total)]
+    }
+}
+
+fn add_entry(total: &mut ProfileEntry, entry: &ProfileEntry) {
+    total.count += entry.count;
+    total.time += entry.time;
+}
+
+fn sorted<T>(mut totals: Vec<(T, ProfileEntry)>) -> Vec<(T, ProfileEntry)> {
+    totals.sort_by(|(_, a), (_, b)| b.time.cmp(&a.time).then(b.count.cmp(&a.count)));
+    totals
+}
+
+/// Writes a report of where the time went, by opcode, line and function.
+pub fn write_profile_report(out: &mut dyn Write, profile: &Profile) -> io::Result<()> {
+    let total = profile_by_function(profile)
+        .first()
+        .map(|(_, entry)| *entry)
+        .unwrap_or_default();
+    writeln!(out, "== profile ==")?;
+    writeln!(
+        out,
+        "{} instructions in {:.3}ms",
+        total.count,
+        total.time.as_secs_f64() * 1000.0
+    )?;
+
+    writeln!(out, "\nBy opcode:")?;
+    for (op_code, entry) in profile_by_opcode(profile) {
+        write_report_row(out, op_code.name(), &entry, &total)?;
+    }
+    writeln!(out, "\nBy line:")?;
+    for (line, entry) in profile_by_line(profile) {
+        write_report_row(out, &format!("line {}", line), &entry, &total)?;
+    }
+    writeln!(out, "\nBy function:")?;
+    for (function, entry) in profile_by_function(profile) {
+        write_report_row(out, &function, &entry, &total)?;
+    }
+    Ok(())
+}
+
+fn write_report_row(
+    out: &mut dyn Write,
+    name: &str,
+    entry: &ProfileEntry,
+    total: &ProfileEntry,
+) -> io::Result<()> {
+    let share = if total.time.as_nanos() == 0 {
+        0.0
+    } else {
+        entry.time.as_secs_f64() / total.time.as_secs_f64() * 100.0
+    };
+    writeln!(
+        out,
+        "  {:<24} {:>12} {:>12.3}ms {:>6.1}%",
+        name,
+        entry.count,
+        entry.time.as_secs_f64() * 1000.0,
+        share
+    )
+}
+
+/// Writes the profile in the collapsed stack format that flamegraph tools
+/// read: one `frame;frame;... weight` line per stack, weighted in
+/// nanoseconds. The innermost frames are the line and the opcode.
+pub fn write_collapsed_stacks(out: &mut dyn Write, profile: &Profile) -> io::Result<()> {
+    for (&(line, byte), entry) in &profile.entries {
+        let op_code = OpCode::try_from(byte).expect("Recorded opcode.");
+        writeln!(
+            out,
+            "script;line {};{} {}",
+            line,
+            op_code.name(),
+            entry.time.as_nanos()
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nanos(nanos: u64) -> Duration {
+        Duration::from_nanos(nanos)
+    }
+
+    // Two lines: line 1 adds twice and returns, line 2 multiplies once but
+    // takes longest.
+    fn sample() -> Profile {
+        let mut profile = Profile::default();
+        record_instruction(&mut profile, 1, OpCode::Add, nanos(100));
+        record_instruction(&mut profile, 1, OpCode::Add, nanos(200));
+        record_instruction(&mut profile, 1, OpCode::Return, nanos(50));
+        record_instruction(&mut profile, 2, OpCode::Multiply, nanos(1000));
+        record_instruction(&mut profile, 2, OpCode::Add, nanos(100));
+        profile
+    }
+
+    fn counts_and_nanos<T: Copy>(totals: &[(T, ProfileEntry)]) -> Vec<(T, u64, u128)> {
+        totals
+            .iter()
+            .map(|&(key, entry)| (key, entry.count, entry.time.as_nanos()))
+            .collect()
+    }
+
+    #[test]
+    fn totals_by_opcode() {
+        assert_eq!(
+            counts_and_nanos(&profile_by_opcode(&sample())),
+            vec![
+                (OpCode::Multiply, 1, 1000),
+                (OpCode::Add, 3, 400),
+                (OpCode::Return, 1, 50),
+            ]
+        );
+    }
+
+    #[test]
+    fn totals_by_line() {
+        assert_eq!(
+            counts_and_nanos(&profile_by_line(&sample())),
+            vec![(2, 2, 1100), (1, 3, 350)]
+        );
+    }
+
+    #[test]
+    fn totals_by_function() {
+        let totals = profile_by_function(&sample());
+        assert_eq!(totals.len(), 1);
+        assert_eq!(totals[0].0, "script");
+        assert_eq!(totals[0].1.count, 5);
+        assert_eq!(totals[0].1.time, nanos(1450));
+
+        assert!(profile_by_function(&Profile::default()).is_empty());
+    }
+
+    #[test]
+    fn ties_go_to_the_most_frequent() {
+        let mut profile = Profile::default();
+        record_instruction(&mut profile, 1, OpCode::Add, nanos(10));
+        record_instruction(&mut profile, 1, OpCode::Negate, nanos(5));
+        record_instruction(&mut profile, 1, OpCode::Negate, nanos(5));
+        let totals = profile_by_opcode(&profile);
+        assert_eq!(totals[0].0, OpCode::Negate);
+        assert_eq!(totals[1].0, OpCode::Add);
+    }
+
+    #[test]
+    fn clearing_forgets_everything() {
+        let mut profile = sample();
+        clear_profile(&mut profile);
+        assert!(profile_by_opcode(&profile).is_empty());
+        assert!(profile_by_line(&profile).is_empty());
+    }
+
+    #[test]
+    fn writes_collapsed_stacks() {
+        let mut stacks = vec![];
+        write_collapsed_stacks(&mut stacks, &sample()).unwrap();
+        assert_eq!(
+            String::from_utf8(stacks).unwrap(),
+            "script;line 1;OP_ADD 300\n\
+             script;line 1;OP_RETURN 50\n\
+             script;line 2;OP_ADD 100\n\
+             script;line 2;OP_MULTIPLY 1000\n"
+        );
+    }
+
+    #[test]
+    fn report_rows_add_up() {
+        let mut report = vec![];
+        write_profile_report(&mut report, &sample()).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("== profile ==\n5 instructions in 0.001ms\n"));
+        let row = |name: &str| {
+            report
+                .lines()
+                .find(|line| line.trim_start().starts_with(name))
+                .unwrap_or_else(|| panic!("No row for {}.", name))
+                .split_whitespace()
+                .skip(name.split_whitespace().count())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(row("OP_MULTIPLY"), "1 0.001ms 69.0%");
+        assert_eq!(row("OP_ADD"), "3 0.000ms 27.6%");
+        assert_eq!(row("line 2"), "2 0.001ms 75.9%");
+        assert_eq!(row("script"), "5 0.001ms 100.0%");
+    }
+}
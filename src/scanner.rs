@@ -3,6 +3,8 @@ pub struct Scanner<'source> {
     start: usize,
     current: usize,
     line: usize,
+    keep_trivia: bool,
+    finished: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -50,6 +52,9 @@ pub enum TokenType {
     Var,
     While,
 
+    Comment,
+    Whitespace,
+
     Error,
     EoF,
 }
@@ -61,21 +66,53 @@ pub struct Token<'source> {
     pub token: &'source str,
 }
 
-pub fn init_scanner(source: &str) -> Scanner {
+pub fn init_scanner(source: &str) -> Scanner<'_> {
     Scanner {
         source,
         start: 0,
         current: 0,
         line: 1,
+        keep_trivia: false,
+        finished: false,
+    }
+}
+
+/// Scans the whole source, ending with the `EoF` token.
+pub fn tokenize(source: &str) -> Vec<Token<'_>> {
+    init_scanner(source).collect()
+}
+
+/// Like `tokenize`, but whitespace and comments come through as `Whitespace`
+/// and `Comment` tokens instead of being skipped, so the lexemes cover the
+/// whole source.
+pub fn tokenize_with_trivia(source: &str) -> Vec<Token<'_>> {
+    let mut scanner = init_scanner(source);
+    scanner.keep_trivia = true;
+    scanner.collect()
+}
+
+impl<'source> Iterator for Scanner<'source> {
+    type Item = Token<'source>;
+
+    fn next(&mut self) -> Option<Token<'source>> {
+        if self.finished {
+            return None;
+        }
+
+        let token = scan_token(self);
+        if token.token_type == TokenType::EoF {
+            self.finished = true;
+        }
+        Some(token)
     }
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_alphabetic() || c == '_'
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_at_end(scanner: &Scanner) -> bool {
@@ -153,13 +190,9 @@ fn skip_whitespace(scanner: &mut Scanner) {
                 scanner.line += 1;
                 advance(scanner);
             }
-            '/' => {
-                if peek_next(scanner) == '/' {
-                    while peek(scanner) != '\n' && !is_at_end(scanner) {
-                        advance(scanner);
-                    }
-                } else {
-                    return;
+            '/' if peek_next(scanner) == '/' => {
+                while peek(scanner) != '\n' && !is_at_end(scanner) {
+                    advance(scanner);
                 }
             }
             _ => return,
@@ -167,6 +200,36 @@ fn skip_whitespace(scanner: &mut Scanner) {
     }
 }
 
+fn trivia<'source>(scanner: &mut Scanner<'source>) -> Option<Token<'source>> {
+    scanner.start = scanner.current;
+    let line = scanner.line;
+
+    let token_type = match peek(scanner) {
+        ' ' | '\r' | '\t' | '\n' => {
+            while let ' ' | '\r' | '\t' | '\n' = peek(scanner) {
+                if advance(scanner) == '\n' {
+                    scanner.line += 1;
+                }
+            }
+            TokenType::Whitespace
+        }
+        '/' if peek_next(scanner) == '/' => {
+            while peek(scanner) != '\n' && !is_at_end(scanner) {
+                advance(scanner);
+            }
+            TokenType::Comment
+        }
+        _ => return None,
+    };
+
+    // Whitespace can span lines, so report the line it started on.
+    Some(Token {
+        token_type,
+        token: &scanner.source[scanner.start..scanner.current],
+        line,
+    })
+}
+
 fn check_keyword(scanner: &Scanner, start: usize, rest: &str, token_type: TokenType) -> TokenType {
     if scanner.current - scanner.start == start + rest.len()
         && &scanner.source[scanner.start + start..scanner.start + start + rest.len()] == rest
@@ -276,7 +339,13 @@ fn string<'source>(scanner: &mut Scanner<'source>) -> Token<'source> {
 }
 
 pub fn scan_token<'source>(scanner: &mut Scanner<'source>) -> Token<'source> {
-    skip_whitespace(scanner);
+    if scanner.keep_trivia {
+        if let Some(token) = trivia(scanner) {
+            return token;
+        }
+    } else {
+        skip_whitespace(scanner);
+    }
     scanner.start = scanner.current;
 
     if is_at_end(scanner) {
@@ -354,3 +423,44 @@ pub fn scan_token<'source>(scanner: &mut Scanner<'source>) -> Token<'source> {
         _ => error_token(scanner, "Unexpected character."),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "// A comment.\nvar x = (1.5 + 2)\t* \"two\";\n\n  print x; // Done.\n";
+
+    #[test]
+    fn trivia_covers_the_source() {
+        let tokens = tokenize_with_trivia(SOURCE);
+        let lexemes: String = tokens.iter().map(|token| token.token).collect();
+        assert_eq!(lexemes, SOURCE);
+
+        let mut offset = 0;
+        for token in &tokens {
+            let line = SOURCE[..offset].matches('\n').count() + 1;
+            assert_eq!(token.line, line, "{:?} '{}'", token.token_type, token.token);
+            offset += token.token.len();
+        }
+        assert!(tokens
+            .iter()
+            .any(|token| token.token_type == TokenType::Comment));
+    }
+
+    #[test]
+    fn tokenize_ends_with_one_eof() {
+        for source in &["", "1 + 2", SOURCE] {
+            let tokens = tokenize(source);
+            let eofs = tokens
+                .iter()
+                .filter(|token| token.token_type == TokenType::EoF)
+                .count();
+            assert_eq!(eofs, 1, "'{}'", source);
+            assert_eq!(tokens.last().unwrap().token_type, TokenType::EoF);
+            assert!(!tokens
+                .iter()
+                .any(|token| token.token_type == TokenType::Whitespace
+                    || token.token_type == TokenType::Comment));
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::prelude::*;
+use std::convert::TryInto;
+
+// A compiled chunk on disk looks like this, with every integer little-endian:
+//
+//   magic        "LOXC"
+//   version      u8
+//   code         u32 length, then the bytes
+//   lines        u32 run count, then (u32 line, u32 length) runs covering
+//                every byte of code
+//   constants    u32 count, then a u8 tag and the value for each
+//
+// Numbers are the only constants so far (tag 0, stored as their f64 bits).
+// Strings and functions will get their own tags once the compiler makes them.
+pub const BYTECODE_MAGIC: &[u8; 4] = b"LOXC";
+pub const BYTECODE_VERSION: u8 = 1;
+
+const TAG_NUMBER: u8 = 0;
+
+pub fn serialize_chunk(chunk: &Chunk) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(BYTECODE_MAGIC);
+    bytes.push(BYTECODE_VERSION);
+
+    write_u32(&mut bytes, chunk.code.len());
+    bytes.extend_from_slice(&chunk.code);
+
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for &line in &chunk.lines {
+        match runs.last_mut() {
+            Some((run_line, length)) if *run_line == line => *length += 1,
+            _ => runs.push((line, 1)),
+        }
+    }
+    write_u32(&mut bytes, runs.len());
+    for (line, length) in runs {
+        write_u32(&mut bytes, line);
+        write_u32(&mut bytes, length);
+    }
+
+    write_u32(&mut bytes, chunk.constants.len());
+    for constant in &chunk.constants {
+        bytes.push(TAG_NUMBER);
+        bytes.extend_from_slice(&constant.to_bits().to_le_bytes());
+    }
+
+    bytes
+}
+
+pub fn deserialize_chunk(bytes: &[u8]) -> Result<Chunk, String> {
+    let mut reader = Reader { bytes, offset: 0 };
+
+    if reader.take(BYTECODE_MAGIC.len())? != BYTECODE_MAGIC {
+        return Err("Not a Lox bytecode file.".to_string());
+    }
+    let version = reader.take(1)?[0];
+    if version != BYTECODE_VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {} (expected {}).",
+            version, BYTECODE_VERSION
+        ));
+    }
+
+    let mut chunk = init_chunk();
+
+    let code_length = reader.read_u32()?;
+    chunk.code = reader.take(code_length)?.to_vec();
+
+    let run_count = reader.read_u32()?;
+    for _ in 0..run_count {
+        let line = reader.read_u32()?;
+        let length = reader.read_u32()?;
+        if chunk.lines.len() + length > code_length {
+            return Err("Line table is longer than the code.".to_string());
+        }
+        chunk.lines.extend(std::iter::repeat_n(line, length));
+    }
+    if chunk.lines.len() != code_length {
+        return Err("Line table is shorter than the code.".to_string());
+    }
+
+    let constant_count = reader.read_u32()?;
+    for index in 0..constant_count {
+        let tag = reader.take(1)?[0];
+        let value = match tag {
+            TAG_NUMBER => {
+                let bits = reader.take(8)?.try_into().expect("Took 8 bytes.");
+                f64::from_bits(u64::from_le_bytes(bits))
+            }
+            _ => return Err(format!("Unknown tag {} for constant {}.", tag, index)),
+        };
+        push_constant(&mut chunk, value);
+    }
+
+    if reader.offset != bytes.len() {
+        return Err(format!(
+            "Unexpected data after the constant pool at byte {}.",
+            reader.offset
+        ));
+    }
+
+    verify_chunk(&chunk)?;
+    Ok(chunk)
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value = value as u32;
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+struct Reader<'bytes> {
+    bytes: &'bytes [u8],
+    offset: usize,
+}
+
+impl<'bytes> Reader<'bytes> {
+    fn take(&mut self, length: usize) -> Result<&'bytes [u8], String> {
+        if self.bytes.len() - self.offset < length {
+            return Err(format!("Unexpected end of file at byte {}.", self.offset));
+        }
+        let taken = &self.bytes[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(taken)
+    }
+
+    fn read_u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?.try_into().expect("Took 4 bytes.");
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compiled(source: &str) -> Chunk {
+        let mut chunk = init_chunk();
+        assert!(compile_with_options(
+            source,
+            &mut chunk,
+            &CompilerOptions::unfolded()
+        ));
+        chunk
+    }
+
+    #[test]
+    fn round_trips() {
+        let chunk = compiled("1 +\n2 * -3");
+        let loaded = deserialize_chunk(&serialize_chunk(&chunk)).unwrap();
+        assert_eq!(loaded.code, chunk.code);
+        assert_eq!(loaded.lines, chunk.lines);
+        assert_eq!(loaded.constants, chunk.constants);
+    }
+
+    #[test]
+    fn loaded_constants_are_reused() {
+        let chunk = compiled("0 + 1");
+        let mut loaded = deserialize_chunk(&serialize_chunk(&chunk)).unwrap();
+        assert_eq!(add_constant(&mut loaded, 0.0), 0);
+        assert_eq!(loaded.constants.len(), 2);
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let bytes = serialize_chunk(&compiled("1 +\n2 * -3"));
+        for length in 0..bytes.len() {
+            assert!(
+                deserialize_chunk(&bytes[..length]).is_err(),
+                "Accepted the first {} bytes.",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut bytes = serialize_chunk(&compiled("1"));
+        bytes.push(0);
+        let message = deserialize_chunk(&bytes)
+            .err()
+            .expect("Accepted trailing data.");
+        assert!(message.starts_with("Unexpected data after the constant pool"));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(
+            deserialize_chunk(b"print 1;").err(),
+            Some("Not a Lox bytecode file.".to_string())
+        );
+    }
+}
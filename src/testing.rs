@@ -0,0 +1,34 @@
+// Helpers shared by the unit tests.
+
+use crate::prelude::*;
+
+pub fn compile_unfolded(source: &str) -> Chunk {
+    let mut chunk = init_chunk();
+    assert!(
+        compile_with_options(source, &mut chunk, &CompilerOptions::unfolded()),
+        "'{}' didn't compile.",
+        source
+    );
+    chunk
+}
+
+/// Runs `chunk` up to its OP_RETURN and returns the value it would print.
+/// The OP_RETURN is moved onto a line of its own so a breakpoint can stop
+/// the VM just before it.
+pub fn run_to_return(chunk: &Chunk) -> Value {
+    let mut chunk = chunk.clone();
+    let last_line = usize::MAX;
+    let instruction = decode_chunk(&chunk)
+        .into_iter()
+        .find(|instruction| instruction.op_code == Ok(OpCode::Return))
+        .expect("The chunk has an OP_RETURN.");
+    chunk.lines[instruction.offset] = last_line;
+
+    let mut vm = init_vm();
+    set_breakpoint(&mut vm, last_line);
+    match interpret_chunk(&mut vm, chunk) {
+        InterpretResult::Paused(line) if line == last_line => (),
+        _ => panic!("The chunk didn't reach its OP_RETURN."),
+    }
+    *stack(&vm).last().expect("The result is on the stack.")
+}
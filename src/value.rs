@@ -1,5 +1,11 @@
+use std::io::{self, Write};
+
 pub type Value = f64;
 
 pub fn print_value(value: Value) {
     print!("{}", value);
 }
+
+pub fn write_value(out: &mut dyn Write, value: Value) -> io::Result<()> {
+    write!(out, "{}", value)
+}
@@ -0,0 +1,159 @@
+use crate::prelude::*;
+use std::convert::TryFrom;
+
+/// Checks that a chunk is safe to hand to the VM: every opcode is known,
+/// every operand is present, every constant index exists, the stack never
+/// underflows, and execution reaches an OP_RETURN instead of running off the
+/// end of the code. There are no jump instructions yet, so control flow is a
+/// straight line and each instruction is simulated once, in order.
+pub fn verify_chunk(chunk: &Chunk) -> Result<(), String> {
+    if chunk.lines.len() != chunk.code.len() {
+        return Err(format!(
+            "Line table has {} entries for {} bytes of code.",
+            chunk.lines.len(),
+            chunk.code.len()
+        ));
+    }
+
+    let mut offset = 0;
+    let mut depth: usize = 0;
+    let mut returned = false;
+    while offset < chunk.code.len() {
+        let instruction = match OpCode::try_from(chunk.code[offset]) {
+            Ok(instruction) => instruction,
+            Err(byte) => return Err(format!("Unknown opcode {} at offset {}.", byte, offset)),
+        };
+        let operand_length = instruction.operand_width();
+        let (pops, pushes) = match instruction {
+            OpCode::Constant | OpCode::ConstantLong => (0, 1),
+            OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide => (2, 1),
+            OpCode::Negate
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant
+            | OpCode::MultiplyConstant
+            | OpCode::DivideConstant => (1, 1),
+            OpCode::Return => (1, 0),
+        };
+        if offset + operand_length >= chunk.code.len() {
+            return Err(format!("Truncated instruction at offset {}.", offset));
+        }
+
+        if operand_length > 0 {
+            let constant = chunk.code[offset + 1..offset + 1 + operand_length]
+                .iter()
+                .fold(0, |index, &byte| index << 8 | byte as usize);
+            if constant >= chunk.constants.len() {
+                return Err(format!(
+                    "Constant {} at offset {} is out of range.",
+                    constant, offset
+                ));
+            }
+        }
+
+        // Anything after the first return is unreachable, so only its
+        // encoding matters.
+        if !returned {
+            if depth < pops {
+                return Err(format!("Stack underflow at offset {}.", offset));
+            }
+            depth = depth - pops + pushes;
+            returned = instruction == OpCode::Return;
+        }
+
+        offset += 1 + operand_length;
+    }
+
+    if !returned {
+        return Err("Execution runs past the end of the code.".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_of(code: &[u8], constants: &[Value]) -> Chunk {
+        let mut chunk = init_chunk();
+        for &byte in code {
+            write_chunk(&mut chunk, byte, 1);
+        }
+        for &value in constants {
+            add_constant(&mut chunk, value);
+        }
+        chunk
+    }
+
+    const CONSTANT: u8 = OpCode::Constant as u8;
+    const CONSTANT_LONG: u8 = OpCode::ConstantLong as u8;
+    const ADD: u8 = OpCode::Add as u8;
+    const NEGATE: u8 = OpCode::Negate as u8;
+    const RETURN: u8 = OpCode::Return as u8;
+
+    #[test]
+    fn accepts_valid_code() {
+        let chunk = chunk_of(&[CONSTANT, 0, NEGATE, RETURN], &[1.0]);
+        assert_eq!(verify_chunk(&chunk), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_opcode() {
+        let chunk = chunk_of(&[CONSTANT, 0, 0xff, RETURN], &[1.0]);
+        assert_eq!(
+            verify_chunk(&chunk),
+            Err("Unknown opcode 255 at offset 2.".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_operand() {
+        let chunk = chunk_of(&[CONSTANT], &[1.0]);
+        assert_eq!(
+            verify_chunk(&chunk),
+            Err("Truncated instruction at offset 0.".to_string())
+        );
+        let chunk = chunk_of(&[CONSTANT_LONG, 0, 0], &[1.0]);
+        assert_eq!(
+            verify_chunk(&chunk),
+            Err("Truncated instruction at offset 0.".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_constant() {
+        let chunk = chunk_of(&[CONSTANT, 1, RETURN], &[1.0]);
+        assert_eq!(
+            verify_chunk(&chunk),
+            Err("Constant 1 at offset 0 is out of range.".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        let chunk = chunk_of(&[CONSTANT, 0, ADD, RETURN], &[1.0]);
+        assert_eq!(
+            verify_chunk(&chunk),
+            Err("Stack underflow at offset 2.".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_missing_return() {
+        let chunk = chunk_of(&[CONSTANT, 0, NEGATE], &[1.0]);
+        assert_eq!(
+            verify_chunk(&chunk),
+            Err("Execution runs past the end of the code.".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_line_table_length() {
+        let mut chunk = chunk_of(&[CONSTANT, 0, RETURN], &[1.0]);
+        chunk.lines.pop();
+        assert_eq!(
+            verify_chunk(&chunk),
+            Err("Line table has 2 entries for 3 bytes of code.".to_string())
+        );
+    }
+}
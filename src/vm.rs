@@ -1,97 +1,563 @@
 use crate::prelude::*;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// How many instructions run between checks of the interrupt flag.
+const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+/// The number of stack slots a new VM gets; see `set_stack_limit`.
+pub const STACK_MAX: usize = 256;
 
 pub struct VM {
     chunk: Option<Chunk>,
     ip: usize,
+    // Allocated up front; only the slots below `stack_top` are live.
     stack: Vec<Value>,
+    stack_top: usize,
+    instruction_budget: Option<u64>,
+    // What the current run has left of `instruction_budget`.
+    budget_left: u64,
+    interrupt: Arc<AtomicBool>,
+    options: VmOptions,
+    profile: Profile,
+    breakpoints: BTreeSet<usize>,
+    // Pause on every new line rather than only at breakpoints.
+    stepping: bool,
+    paused_line: Option<usize>,
+}
+
+/// Debugging output, all off by default.
+#[derive(Clone, Default)]
+pub struct VmOptions {
+    /// Disassemble each chunk `interpret` compiles.
+    pub print_code: bool,
+    /// Print the stack and each instruction as it runs.
+    pub trace_execution: bool,
+    /// Count and time every instruction run; see `profile`.
+    pub profile: bool,
 }
 
 pub enum InterpretResult {
     Ok,
     CompileError,
-    RuntimeError,
+    RuntimeError(RuntimeError),
+    BudgetExhausted(RuntimeError),
+    Interrupted(RuntimeError),
+    /// Stopped before running the given line, at a breakpoint or after a
+    /// step; see `resume` and `step`.
+    Paused(usize),
+}
+
+/// What went wrong and the call stack at the time, innermost call first.
+pub struct RuntimeError {
+    pub message: String,
+    pub trace: Vec<TraceFrame>,
+}
+
+pub struct TraceFrame {
+    /// `None` for the top-level script.
+    pub function: Option<String>,
+    pub line: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.function {
+            Some(ref name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
 }
 
 pub fn init_vm() -> VM {
+    init_vm_with_options(VmOptions::default())
+}
+
+pub fn init_vm_with_options(options: VmOptions) -> VM {
     VM {
         chunk: None,
         ip: 0,
-        stack: vec![],
+        stack: vec![0.0; STACK_MAX],
+        stack_top: 0,
+        instruction_budget: None,
+        budget_left: u64::MAX,
+        interrupt: Arc::new(AtomicBool::new(false)),
+        options,
+        profile: Profile::default(),
+        breakpoints: BTreeSet::new(),
+        stepping: false,
+        paused_line: None,
     }
 }
 pub fn free_vm(_vm: VM) {
     // Just take it and don't let it go.
 }
 
+/// Sets how many values the stack can hold. Pushing past it raises a
+/// "Stack overflow." runtime error. This empties the stack, so it also ends
+/// a paused run; `resume` and `step` then have nothing to continue.
+pub fn set_stack_limit(vm: &mut VM, slots: usize) {
+    vm.stack.resize(slots, 0.0);
+    // run() trusts the stack depth to match ip, so the run can't carry on.
+    restart(vm);
+}
+
+/// Limits each run to `budget` instructions, after which it stops with
+/// `InterpretResult::BudgetExhausted`. `None` lets scripts run forever. A
+/// paused run keeps the budget it started with, and pausing and resuming
+/// doesn't reset it.
+pub fn set_instruction_budget(vm: &mut VM, budget: Option<u64>) {
+    vm.instruction_budget = budget;
+}
+
+/// Returns a flag that, once set from any thread, stops the running script
+/// with `InterpretResult::Interrupted`. The VM clears it when it stops on it,
+/// and when it starts a new chunk, so a request that comes too late for one
+/// script doesn't stop the next.
+pub fn interrupt_handle(vm: &VM) -> Arc<AtomicBool> {
+    Arc::clone(&vm.interrupt)
+}
+
+/// Turns `VmOptions::trace_execution` on or off between runs.
+pub fn set_trace_execution(vm: &mut VM, trace: bool) {
+    vm.options.trace_execution = trace;
+}
+
+/// What the VM has run so far, if `VmOptions::profile` is set.
+pub fn profile(vm: &VM) -> &Profile {
+    &vm.profile
+}
+
+pub fn reset_profile(vm: &mut VM) {
+    clear_profile(&mut vm.profile);
+}
+
+/// Makes runs pause with `InterpretResult::Paused` before the first
+/// instruction of `line` each time execution reaches it from another line.
+pub fn set_breakpoint(vm: &mut VM, line: usize) {
+    vm.breakpoints.insert(line);
+}
+
+pub fn clear_breakpoint(vm: &mut VM, line: usize) {
+    vm.breakpoints.remove(&line);
+}
+
+pub fn breakpoints(vm: &VM) -> &BTreeSet<usize> {
+    &vm.breakpoints
+}
+
+/// The line the VM is paused before, if it's paused.
+pub fn paused_line(vm: &VM) -> Option<usize> {
+    vm.paused_line
+}
+
+/// The values currently on the stack, bottom first.
+pub fn stack(vm: &VM) -> &[Value] {
+    &vm.stack[..vm.stack_top]
+}
+
+fn reset_stack(vm: &mut VM) {
+    vm.stack_top = 0;
+}
+
+fn runtime_error(vm: &mut VM, message: &str) -> RuntimeError {
+    let mut trace = vec![];
+    if let Some(ref chunk) = vm.chunk {
+        trace.push(TraceFrame {
+            function: None,
+            // The run can stop before reading an instruction, when the
+            // budget or an interrupt catches it at the start.
+            line: chunk.lines[vm.ip.saturating_sub(1)],
+        });
+    }
+
+    eprintln!("{}", message);
+    for frame in &trace {
+        eprintln!("{}", frame);
+    }
+
+    reset_stack(vm);
+    RuntimeError {
+        message: message.to_string(),
+        trace,
+    }
+}
+
 pub fn interpret(vm: &mut VM, source: &str) -> InterpretResult {
     let mut chunk = init_chunk();
 
-    if !compile(source, &mut chunk) {
+    let options = CompilerOptions {
+        print_code: vm.options.print_code,
+        ..CompilerOptions::default()
+    };
+    if !compile_with_options(source, &mut chunk, &options) {
         free_chunk(chunk);
         InterpretResult::CompileError
     } else {
-        vm.chunk = Some(chunk);
-        vm.ip = 0;
+        interpret_chunk(vm, chunk)
+    }
+}
 
-        run(vm)
+/// Runs an already compiled chunk, such as one loaded from a bytecode file.
+pub fn interpret_chunk(vm: &mut VM, chunk: Chunk) -> InterpretResult {
+    if !load_chunk(vm, chunk) {
+        return InterpretResult::CompileError;
     }
+    vm.stepping = false;
+    run_vm(vm)
 }
 
-fn run(vm: &mut VM) -> InterpretResult {
-    if let Some(ref mut chunk) = vm.chunk {
-        loop {
-            if DEBUG_TRACE_EXECUTION {
-                print!("          ");
-                for slot in &vm.stack {
-                    print!("[ ");
-                    print_value(*slot);
-                    print!(" ]");
-                }
-                println!("");
-                disassemble_instruction(chunk, vm.ip);
+/// Like `interpret_chunk`, but pauses before the first line, as if stepping
+/// into it.
+pub fn debug_chunk(vm: &mut VM, chunk: Chunk) -> InterpretResult {
+    if !load_chunk(vm, chunk) {
+        return InterpretResult::CompileError;
+    }
+    vm.stepping = true;
+    run_vm(vm)
+}
+
+/// Carries on from where the VM paused until the next breakpoint. Does
+/// nothing and returns `InterpretResult::Ok` if it isn't paused.
+pub fn resume(vm: &mut VM) -> InterpretResult {
+    if vm.paused_line.is_none() {
+        return InterpretResult::Ok;
+    }
+    vm.stepping = false;
+    run_vm(vm)
+}
+
+/// Runs the paused line and pauses again before the next one. There are no
+/// calls yet, so stepping into, over and out of a line are all the same.
+/// Does nothing and returns `InterpretResult::Ok` if the VM isn't paused.
+pub fn step(vm: &mut VM) -> InterpretResult {
+    if vm.paused_line.is_none() {
+        return InterpretResult::Ok;
+    }
+    vm.stepping = true;
+    run_vm(vm)
+}
+
+/// Runs the chunk the VM last ran again from the start, without verifying
+/// it again, for benchmarks and restarting in the debugger. Returns
+/// `InterpretResult::CompileError` if there's no chunk to run.
+pub fn rerun(vm: &mut VM) -> InterpretResult {
+    restart(vm);
+    vm.stepping = false;
+    run_vm(vm)
+}
+
+fn load_chunk(vm: &mut VM, chunk: Chunk) -> bool {
+    // run() trusts the code it's given, so check it first.
+    if let Err(message) = verify_chunk(&chunk) {
+        eprintln!("Invalid chunk: {}", message);
+        return false;
+    }
+
+    vm.chunk = Some(chunk);
+    restart(vm);
+    true
+}
+
+fn restart(vm: &mut VM) {
+    vm.ip = 0;
+    vm.budget_left = vm.instruction_budget.unwrap_or(u64::MAX);
+    vm.paused_line = None;
+    vm.interrupt.store(false, Ordering::Relaxed);
+    reset_stack(vm);
+}
+
+fn run_vm(vm: &mut VM) -> InterpretResult {
+    let debug = vm.stepping || !vm.breakpoints.is_empty();
+    match (vm.options.trace_execution, vm.options.profile, debug) {
+        (false, false, false) => run::<false, false, false>(vm),
+        (false, false, true) => run::<false, false, true>(vm),
+        (false, true, false) => run::<false, true, false>(vm),
+        (false, true, true) => run::<false, true, true>(vm),
+        (true, false, false) => run::<true, false, false>(vm),
+        (true, false, true) => run::<true, false, true>(vm),
+        (true, true, false) => run::<true, true, false>(vm),
+        (true, true, true) => run::<true, true, true>(vm),
+    }
+}
+
+// The chunk has been through verify_chunk, so every opcode is known, every
+// operand and constant index is in range, the stack never underflows and the
+// code returns before running off the end. That lets the loop below read code,
+// constants and stack slots without bounds checks. Pushes still check for
+// overflow, since the stack has a fixed size.
+//
+// Without a budget, `remaining` starts so high it never runs out. What's left
+// of it is kept in `vm.budget_left` across pauses, so stepping through a
+// script doesn't top it up. The interrupt flag is checked on the first
+// instruction and then every INTERRUPT_CHECK_INTERVAL, counted separately so
+// the budget doesn't shift when the checks happen.
+//
+// Tracing, profiling and debugging are const parameters so the plain loop
+// doesn't even test for them. When profiling, each instruction is recorded
+// once the next one starts, or when the loop stops. When debugging, the loop
+// checks for breakpoints whenever it gets to a new line; it starts again from
+// `vm.ip` after a pause, and a resumed run doesn't pause again on the same
+// line.
+fn run<const TRACE: bool, const PROFILE: bool, const DEBUG: bool>(vm: &mut VM) -> InterpretResult {
+    let chunk = match vm.chunk {
+        Some(ref chunk) => chunk,
+        None => return InterpretResult::CompileError,
+    };
+    let code = &chunk.code;
+    let constants = &chunk.constants;
+    let stack = &mut vm.stack;
+    let stack_max = stack.len();
+    let mut ip = vm.ip;
+    let mut top = vm.stack_top;
+    let interrupt = &vm.interrupt;
+    let mut remaining = vm.budget_left;
+    let mut executed: u64 = 0;
+    let profile = &mut vm.profile;
+    let mut profiled: Option<(usize, OpCode, Instant)> = None;
+    let breakpoints = &vm.breakpoints;
+    let stepping = vm.stepping;
+    let mut previous_line = vm.paused_line.take();
+
+    macro_rules! read_byte {
+        () => {{
+            let byte = unsafe { *code.get_unchecked(ip) };
+            ip += 1;
+            byte
+        }};
+    }
+
+    macro_rules! read_constant {
+        ($index:expr) => {{
+            let index = $index;
+            unsafe { *constants.get_unchecked(index) }
+        }};
+    }
+
+    macro_rules! push {
+        ($value:expr) => {{
+            let value = $value;
+            if top == stack_max {
+                break (InterpretResult::RuntimeError, "Stack overflow.");
             }
-            let instruction = chunk.code[vm.ip];
-            vm.ip += 1;
-            match instruction {
-                OP_CONSTANT => {
-                    let constant_index = chunk.code[vm.ip];
-                    vm.ip += 1;
-                    let constant = chunk.constants[constant_index as usize];
-                    vm.stack.push(constant);
-                }
-                OP_ADD => {
-                    let b = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    let a = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    vm.stack.push(a + b);
-                }
-                OP_SUBTRACT => {
-                    let b = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    let a = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    vm.stack.push(a - b);
-                }
-                OP_MULTIPLY => {
-                    let b = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    let a = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    vm.stack.push(a * b);
-                }
-                OP_DIVIDE => {
-                    let b = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    let a = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    vm.stack.push(a / b);
-                }
-                OP_NEGATE => {
-                    let value = vm.stack.pop().expect("Tried to pop an empty stack.");
-                    vm.stack.push(-value);
-                }
-                OP_RETURN => {
-                    print_value(vm.stack.pop().expect("Tried to pop an empty stack."));
-                    println!("");
-                    return InterpretResult::Ok;
+            unsafe { *stack.get_unchecked_mut(top) = value };
+            top += 1;
+        }};
+    }
+
+    macro_rules! pop {
+        () => {{
+            top -= 1;
+            unsafe { *stack.get_unchecked(top) }
+        }};
+    }
+
+    // Leaves the result in the left operand's slot instead of popping both.
+    macro_rules! binary_op {
+        ($op:tt) => {{
+            let b = pop!();
+            let a = unsafe { stack.get_unchecked_mut(top - 1) };
+            *a $op b;
+        }};
+    }
+
+    // Like binary_op, with the right operand coming from the constant pool.
+    macro_rules! constant_op {
+        ($op:tt) => {{
+            let b = read_constant!(read_byte!() as usize);
+            let a = unsafe { stack.get_unchecked_mut(top - 1) };
+            *a $op b;
+        }};
+    }
+
+    macro_rules! record_profiled {
+        () => {
+            if PROFILE {
+                if let Some((line, op_code, start)) = profiled.take() {
+                    record_instruction(profile, line, op_code, start.elapsed());
                 }
-                _ => return InterpretResult::RuntimeError,
             }
+        };
+    }
+
+    let (result, message): (fn(RuntimeError) -> InterpretResult, &str) = loop {
+        record_profiled!();
+
+        if DEBUG {
+            let line = chunk.lines[ip];
+            if previous_line != Some(line) && (stepping || breakpoints.contains(&line)) {
+                vm.ip = ip;
+                vm.stack_top = top;
+                vm.paused_line = Some(line);
+                vm.budget_left = remaining;
+                return InterpretResult::Paused(line);
+            }
+            previous_line = Some(line);
         }
-    } else {
-        InterpretResult::CompileError
+
+        if remaining == 0 {
+            break (
+                InterpretResult::BudgetExhausted,
+                "Instruction budget exhausted.",
+            );
+        }
+        remaining -= 1;
+        if executed.is_multiple_of(INTERRUPT_CHECK_INTERVAL)
+            && interrupt.swap(false, Ordering::Relaxed)
+        {
+            break (InterpretResult::Interrupted, "Interrupted.");
+        }
+        executed = executed.wrapping_add(1);
+
+        if TRACE {
+            print!("          ");
+            for slot in &stack[..top] {
+                print!("[ ");
+                print_value(*slot);
+                print!(" ]");
+            }
+            println!();
+            disassemble_instruction(chunk, ip);
+        }
+
+        let offset = ip;
+        let instruction = match OpCode::try_from(read_byte!()) {
+            Ok(instruction) => instruction,
+            Err(_) => break (InterpretResult::RuntimeError, "Unknown opcode."),
+        };
+        if PROFILE {
+            profiled = Some((chunk.lines[offset], instruction, Instant::now()));
+        }
+        match instruction {
+            OpCode::Constant => {
+                let constant_index = read_byte!();
+                push!(read_constant!(constant_index as usize));
+            }
+            OpCode::ConstantLong => {
+                let constant_index = (read_byte!() as usize) << 16
+                    | (read_byte!() as usize) << 8
+                    | read_byte!() as usize;
+                push!(read_constant!(constant_index));
+            }
+            OpCode::Add => binary_op!(+=),
+            OpCode::Subtract => binary_op!(-=),
+            OpCode::Multiply => binary_op!(*=),
+            OpCode::Divide => binary_op!(/=),
+            OpCode::AddConstant => constant_op!(+=),
+            OpCode::SubtractConstant => constant_op!(-=),
+            OpCode::MultiplyConstant => constant_op!(*=),
+            OpCode::DivideConstant => constant_op!(/=),
+            OpCode::Negate => {
+                let value = unsafe { stack.get_unchecked_mut(top - 1) };
+                *value = -*value;
+            }
+            OpCode::Return => {
+                print_value(pop!());
+                println!();
+                record_profiled!();
+                vm.ip = ip;
+                vm.stack_top = top;
+                return InterpretResult::Ok;
+            }
+        }
+    };
+    record_profiled!();
+
+    vm.ip = ip;
+    vm.stack_top = top;
+    let error = runtime_error(vm, message);
+    result(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_can_run_out_before_the_first_instruction() {
+        let mut vm = init_vm();
+        set_instruction_budget(&mut vm, Some(0));
+        match interpret(&mut vm, "1 + 2") {
+            InterpretResult::BudgetExhausted(error) => assert_eq!(error.trace[0].line, 1),
+            _ => panic!("Expected the budget to run out."),
+        }
+    }
+
+    #[test]
+    fn interrupt_can_stop_the_first_instruction() {
+        for &budget in &[None, Some(1), Some(5), Some(INTERRUPT_CHECK_INTERVAL + 7)] {
+            let mut vm = init_vm();
+            set_instruction_budget(&mut vm, budget);
+            let interrupt = interrupt_handle(&vm);
+            // Set after loading, as another thread would.
+            assert!(load_chunk(&mut vm, chunk_returning_one()));
+            interrupt.store(true, Ordering::Relaxed);
+            match run_vm(&mut vm) {
+                InterpretResult::Interrupted(error) => assert_eq!(error.trace[0].line, 1),
+                _ => panic!("Budget {:?} wasn't interrupted.", budget),
+            }
+        }
+    }
+
+    #[test]
+    fn stale_interrupt_does_not_stop_the_next_script() {
+        let mut vm = init_vm();
+        interrupt_handle(&vm).store(true, Ordering::Relaxed);
+        assert!(matches!(interpret(&mut vm, "1 + 2"), InterpretResult::Ok));
+        assert!(!interrupt_handle(&vm).load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn changing_the_stack_limit_ends_a_paused_run() {
+        let mut chunk = init_chunk();
+        assert!(compile_with_options(
+            "1\n+\n2",
+            &mut chunk,
+            &CompilerOptions::unfolded()
+        ));
+
+        let mut vm = init_vm();
+        set_breakpoint(&mut vm, 3);
+        assert!(matches!(
+            interpret_chunk(&mut vm, chunk),
+            InterpretResult::Paused(3)
+        ));
+        set_stack_limit(&mut vm, 10);
+        assert_eq!(paused_line(&vm), None);
+        assert!(matches!(resume(&mut vm), InterpretResult::Ok));
+        assert!(stack(&vm).is_empty());
+    }
+
+    #[test]
+    fn pausing_keeps_the_budget() {
+        let mut chunk = init_chunk();
+        assert!(compile_with_options(
+            "1\n+\n2\n+\n3",
+            &mut chunk,
+            &CompilerOptions::unfolded()
+        ));
+
+        // Six instructions, so a budget of five runs out on OP_RETURN
+        // however often the run pauses.
+        let mut vm = init_vm();
+        set_instruction_budget(&mut vm, Some(5));
+        let mut result = debug_chunk(&mut vm, chunk);
+        while let InterpretResult::Paused(_) = result {
+            result = step(&mut vm);
+        }
+        assert!(matches!(result, InterpretResult::BudgetExhausted(_)));
+    }
+
+    fn chunk_returning_one() -> Chunk {
+        let mut chunk = init_chunk();
+        let constant = add_constant(&mut chunk, 1.0);
+        write_chunk(&mut chunk, OpCode::Constant as u8, 1);
+        write_chunk(&mut chunk, constant as u8, 1);
+        write_chunk(&mut chunk, OpCode::Return as u8, 1);
+        chunk
     }
 }